@@ -0,0 +1,28 @@
+//! 页表操作需要由内核或测试环境提供的内存分配接口
+use super::structures::pptr_t;
+
+/// 页表分配器，用于在遍历页表时创建缺失的中间页表
+/// 
+/// Page table allocator used to create missing intermediate tables.
+pub trait PageTableAllocator {
+    /// 分配一个按`4KB`对齐的页表，返回其在`PSpace`中的虚拟地址，分配失败时返回`None`，
+    /// 返回的页表无需清零
+    fn alloc_page_table(&mut self) -> Option<pptr_t>;
+}
+
+/// 页表回收接口，用于在销毁地址空间时归还中间页表
+/// 
+/// Page table deallocator that receives the intermediate tables of a destroyed vspace.
+pub trait PageTableDeallocator {
+    /// 归还一个页表，`pt`为其在`PSpace`中的虚拟地址
+    fn free_page_table(&mut self, pt: pptr_t);
+}
+
+/// 页面分配器，用于在复制地址空间等操作中分配新的页面
+/// 
+/// Frame allocator used when frame contents have to be copied.
+pub trait FrameAllocator {
+    /// 分配一个大小为`page_size`（`RISCV_4K_Page`、`RISCV_Mega_Page`、`RISCV_Giga_Page`）且按其大小对齐的页面，
+    /// 返回其在`PSpace`中的虚拟地址，分配失败时返回`None`
+    fn alloc_frame(&mut self, page_size: usize) -> Option<pptr_t>;
+}
@@ -1,10 +1,13 @@
 use core::arch::asm;
+use core::fmt;
 use core::intrinsics::unlikely;
 
 use sel4_common::{structures::exception_t, sel4_config::*, utils::convert_to_option_mut_type_ref, fault::*, BIT, MASK};
 use sel4_cspace::interface::cap_t;
-use crate::structures::pptr_t;
-use super::{pte::pte_t, interface::set_vm_root};
+use crate::structures::{pptr_t, AsidError};
+use super::{pte::pte_t, interface::set_vm_root, satp::get_hw_asid_bits, hw_asid::{hw_asid, release_hw_asid}};
+#[cfg(feature = "ENABLE_SMP")]
+use super::satp::remote_sfence;
 
 ///存放`asid pool`的数组，每一个下标对应一个`asid pool`，
 ///一个`asid pool`可以存放`asidLowBits`个asid值
@@ -27,25 +30,263 @@ impl asid_pool_t {
     
     #[inline]
     pub fn get_vspace_by_index(&mut self, index: usize) -> Option<&'static mut pte_t> {
-        convert_to_option_mut_type_ref::<pte_t>(self.array[index] as usize)
+        convert_to_option_mut_type_ref::<pte_t>(*self.array.get(index)? as usize)
     }
 
+    /// 设置`asid pool`中下标为`index`的根页表，`index`越界时返回`AsidError::OutOfRange`
     #[inline]
-    pub fn set_vspace_by_index(&mut self, index: usize, vspace_ptr: pptr_t) {
-        // assert!(index < BIT!(asidLowBits));
-        self.array[index] = vspace_ptr as *mut pte_t;
+    pub fn set_vspace_by_index(&mut self, index: usize, vspace_ptr: pptr_t) -> Result<(), AsidError> {
+        if unlikely(index >= BIT!(asidLowBits)) {
+            return Err(AsidError::OutOfRange);
+        }
+        unsafe { self.set_vspace_by_index_unchecked(index, vspace_ptr) };
+        Ok(())
+    }
+
+    /// 不检查下标的`set_vspace_by_index`，开启`ENABLE_ASID_ASSERT`时会检查下标
+    /// 
+    /// # Safety
+    /// 
+    /// 调用者需保证`index < BIT!(asidLowBits)`，否则会越界写入`asid pool`之后的内存
+    /// 
+    /// The caller must guarantee `index < BIT!(asidLowBits)`.
+    #[inline]
+    pub unsafe fn set_vspace_by_index_unchecked(&mut self, index: usize, vspace_ptr: pptr_t) {
+        #[cfg(feature = "ENABLE_ASID_ASSERT")]
+        assert!(index < BIT!(asidLowBits));
+        *self.array.get_unchecked_mut(index) = vspace_ptr as *mut pte_t;
+    }
+
+    #[inline]
+    pub fn get_vspace_by_asid(&mut self, asid: Asid) -> Option<&'static mut pte_t> {
+        self.get_vspace_by_index(asid.slot_index())
+    }
+
+    #[inline]
+    pub fn set_vspace_by_asid(&mut self, asid: Asid, vspace_ptr: pptr_t) {
+        // `slot_index`只取低`asidLowBits`位，不会越界
+        unsafe { self.set_vspace_by_index_unchecked(asid.slot_index(), vspace_ptr) }
+    }
+
+    /// 寻找`asid pool`中第一个空闲的下标，用于`ASIDPool Assign`，
+    /// `asid_base`为该`asid pool`的起始`asid`，`asidInvalid`对应的下标不会被分配
+    /// 
+    /// Find the first free slot of the pool.
+    pub fn find_free_index(&self, asid_base: asid_t) -> Result<usize, AsidError> {
+        (0..BIT!(asidLowBits))
+            .find(|&index| asid_base + index != asidInvalid && self.array[index] as usize == 0)
+            .ok_or(AsidError::PoolFull)
     }
 }
 
 /// 进程对应的asid所属的类型
 pub type asid_t = usize;
 
+/// 每个`asid`的元数据，与`asid pool`中的根页表一一对应，但存放在单独的`riscvKSASIDMeta`中，
+/// 一个`asid pool`的全部元数据恰好占用一个`4KB`页面
+/// 
+/// 低32位为硬件`asid`上下文（代数和硬件`asid`），之后8位为该`asid`可能在`TLB`中留有表项的核的掩码（按核的下标而不是`hart id`记录），
+/// 最高24位为该`asid`中映射的页面数
+/// 
+/// Per-asid metadata kept alongside the vspace root pointer.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct asid_meta_t {
+    pub words: [usize; 1],
+}
+
+/// 一个`asid pool`对应的全部`asid`元数据
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct asid_meta_pool_t {
+    pub array: [asid_meta_t; BIT!(asidLowBits)],
+}
+
+const _: () = assert!(core::mem::size_of::<asid_pool_t>() == BIT!(seL4_PageBits));
+const _: () = assert!(core::mem::size_of::<asid_meta_pool_t>() == BIT!(seL4_PageBits));
+// 核掩码按核的下标记录，8位足以表示所有核；发送`SBI`请求前由`satp::remote_sfence`转换为`hart id`掩码
+const _: () = assert!(CONFIG_MAX_NUM_NODES <= 8);
+
+/// 存放`asid`元数据的数组，与`riscvKSASIDTable`使用相同的下标，`asid pool`本身仍只占用一个`4KB`页面
+#[no_mangle]
+pub static mut riscvKSASIDMeta: [asid_meta_pool_t; BIT!(asidHighBits)] =
+    [asid_meta_pool_t { array: [asid_meta_t { words: [0] }; BIT!(asidLowBits)] }; BIT!(asidHighBits)];
+
+/// `asid`元数据相关操作
+impl asid_meta_t {
+    /// 硬件`asid`上下文，高位为分配时的代数，低位为硬件`asid`，为0表示当前没有硬件`asid`
+    #[inline]
+    pub fn get_hw_context(&self) -> u32 {
+        (self.words[0] & 0xffff_ffffusize) as u32
+    }
+
+    #[inline]
+    pub fn set_hw_context(&mut self, context: u32) {
+        self.words[0] &= !0xffff_ffffusize;
+        self.words[0] |= context as usize;
+    }
+
+    /// 该`asid`被激活过、且之后还没有清除对应`TLB`项的核的掩码，第`i`位对应下标为`i`的核
+    #[inline]
+    pub fn get_hart_mask(&self) -> usize {
+        (self.words[0] & 0xff_0000_0000usize) >> 32
+    }
+
+    #[inline]
+    pub fn set_hart_mask(&mut self, mask: usize) {
+        self.words[0] &= !0xff_0000_0000usize;
+        self.words[0] |= (mask << 32) & 0xff_0000_0000usize;
+    }
+
+    #[inline]
+    pub fn add_hart(&mut self, cpu: usize) {
+        self.set_hart_mask(self.get_hart_mask() | BIT!(cpu));
+    }
+
+    /// 该`asid`中映射的页面数，由`pte_t::update`以及本`crate`中直接修改叶子节点的接口维护
+    #[inline]
+    pub fn get_mapping_count(&self) -> usize {
+        (self.words[0] & 0xffff_ff00_0000_0000usize) >> 40
+    }
+
+    #[inline]
+    pub fn set_mapping_count(&mut self, count: usize) {
+        self.words[0] &= !0xffff_ff00_0000_0000usize;
+        self.words[0] |= (count << 40) & 0xffff_ff00_0000_0000usize;
+    }
+
+    /// 映射页面数加一，达到上限时保持不变
+    #[inline]
+    pub fn inc_mapping_count(&mut self) {
+        self.add_mapping_count(1);
+    }
+
+    /// 映射页面数减一，已经为0时保持不变
+    #[inline]
+    pub fn dec_mapping_count(&mut self) {
+        self.sub_mapping_count(1);
+    }
+
+    /// 映射页面数增加`count`，最多增加到上限
+    #[inline]
+    pub fn add_mapping_count(&mut self, count: usize) {
+        self.set_mapping_count(self.get_mapping_count().saturating_add(count).min(MASK!(24)));
+    }
+
+    /// 映射页面数减少`count`，最少减少到0
+    #[inline]
+    pub fn sub_mapping_count(&mut self, count: usize) {
+        self.set_mapping_count(self.get_mapping_count().saturating_sub(count));
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.words[0] = 0;
+    }
+}
+
+/// 获得`asid`对应的元数据，`asid`超出范围时返回`None`
+/// 
+/// Get the metadata of `asid`.
+#[inline]
+pub fn get_asid_meta(asid: asid_t) -> Option<&'static mut asid_meta_t> {
+    let asid = Asid::new(asid)?;
+    unsafe {
+        Some(&mut *core::ptr::addr_of_mut!(riscvKSASIDMeta[asid.pool_index()].array[asid.slot_index()]))
+    }
+}
+
+/// 在`asid`的元数据中记录新映射了`count`个页面，`asid`超出范围时忽略
+#[inline]
+pub(crate) fn add_mapping_count(asid: asid_t, count: usize) {
+    if let Some(meta) = get_asid_meta(asid) {
+        meta.add_mapping_count(count);
+    }
+}
+
+/// 在`asid`的元数据中记录撤销了`count`个页面的映射，`asid`超出范围时忽略
+#[inline]
+pub(crate) fn sub_mapping_count(asid: asid_t, count: usize) {
+    if let Some(meta) = get_asid_meta(asid) {
+        meta.sub_mapping_count(count);
+    }
+}
+
+/// 经过范围检查的`asid`，高`asidHighBits`位为`riscvKSASIDTable`的下标，低`asidLowBits`位为`asid pool`中的下标
+/// 
+/// An asid that has been checked against `asidHighBits` and `asidLowBits`.
+#[repr(transparent)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Asid(asid_t);
+
+impl Asid {
+    /// `asid`超出`asidHighBits + asidLowBits`位时返回`None`
+    #[inline]
+    pub fn new(asid: asid_t) -> Option<Self> {
+        if asid < BIT!(asidHighBits + asidLowBits) {
+            Some(Asid(asid))
+        } else {
+            None
+        }
+    }
+
+    /// 由`riscvKSASIDTable`中的下标和`asid pool`中的下标组成`asid`，任一下标越界时返回`None`
+    #[inline]
+    pub fn from_parts(pool_index: usize, slot_index: usize) -> Option<Self> {
+        if pool_index < BIT!(asidHighBits) && slot_index < BIT!(asidLowBits) {
+            Some(Asid((pool_index << asidLowBits) | slot_index))
+        } else {
+            None
+        }
+    }
+
+    /// 该`asid`所在的`asid pool`在`riscvKSASIDTable`中的下标
+    #[inline]
+    pub fn pool_index(&self) -> usize {
+        self.0 >> asidLowBits
+    }
+
+    /// 该`asid`在`asid pool`中的下标
+    #[inline]
+    pub fn slot_index(&self) -> usize {
+        self.0 & MASK!(asidLowBits)
+    }
+
+    #[inline]
+    pub fn get(&self) -> asid_t {
+        self.0
+    }
+}
+
+impl From<Asid> for asid_t {
+    #[inline]
+    fn from(asid: Asid) -> Self {
+        asid.0
+    }
+}
+
+impl fmt::Display for Asid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Debug for Asid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Asid({:#x}, pool: {}, slot: {})", self.0, self.pool_index(), self.slot_index())
+    }
+}
+
+/// `findVSpaceForASID`的返回值，与`C`代码中的结构体布局一致：
+/// `status`为`EXCEPTION_NONE`时`vspace_root`有效，否则`vspace_root`为空指针，`lookup_fault`记录查找失败的原因
+/// 
+/// C-compatible result of `findVSpaceForASID`.
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct findVSpaceForASID_ret {
     pub status: exception_t,
-    pub vspace_root: Option<*mut pte_t>,
-    pub lookup_fault: Option<lookup_fault_t>,
+    pub vspace_root: *mut pte_t,
+    pub lookup_fault: lookup_fault_t,
 }
 
 /// `riscvKSASIDSpace`寻找对应`index`的`asid pool`
@@ -61,71 +302,110 @@ pub fn get_asid_pool_by_index(index: usize) -> Option<&'static mut asid_pool_t>
     }
 }
 
-/// `riscvKSASIDSpace`设置对应`index`的`asid pool`
+/// `riscvKSASIDSpace`设置对应`index`的`asid pool`，`index`越界时返回`AsidError::OutOfRange`
 /// 
 /// From `riscvKSASIDSpace` set the index-relevant asid pool.
-pub fn set_asid_pool_by_index(index: usize, pool_ptr: pptr_t) {
-    // assert!(index < BIT!(asidHighBits));
-    unsafe {
-        riscvKSASIDTable[index] = pool_ptr as *mut asid_pool_t;
+pub fn set_asid_pool_by_index(index: usize, pool_ptr: pptr_t) -> Result<(), AsidError> {
+    if unlikely(index >= BIT!(asidHighBits)) {
+        return Err(AsidError::OutOfRange);
     }
+    unsafe { set_asid_pool_by_index_unchecked(index, pool_ptr) };
+    Ok(())
+}
+
+/// 不检查下标的`set_asid_pool_by_index`，开启`ENABLE_ASID_ASSERT`时会检查下标
+/// 
+/// From `riscvKSASIDSpace` set the index-relevant asid pool without bounds checking.
+/// 
+/// # Safety
+/// 
+/// 调用者需保证`index < BIT!(asidHighBits)`，否则会越界写入`riscvKSASIDTable`之后的内存
+/// 
+/// The caller must guarantee `index < BIT!(asidHighBits)`.
+#[inline]
+pub unsafe fn set_asid_pool_by_index_unchecked(index: usize, pool_ptr: pptr_t) {
+    #[cfg(feature = "ENABLE_ASID_ASSERT")]
+    assert!(index < BIT!(asidHighBits));
+    *(core::ptr::addr_of_mut!(riscvKSASIDTable) as *mut *mut asid_pool_t).add(index) = pool_ptr as *mut asid_pool_t;
+}
+
+/// 寻找`riscvKSASIDTable`中第一个空闲的下标，用于`ASIDControl MakePool`
+/// 
+/// Find the first free index of `riscvKSASIDTable`.
+pub fn find_free_asid_pool_index() -> Result<usize, AsidError> {
+    (0..BIT!(asidHighBits))
+        .find(|&index| unsafe { riscvKSASIDTable[index].is_null() })
+        .ok_or(AsidError::NoFreePool)
 }
 
 ///根据给定的`asid`在`riscvKSASIDTable`中寻找对应的虚拟地址空间页表基址
 /// 
 /// Find the root page table associated with asid.
-#[no_mangle]
-pub fn find_vspace_for_asid(asid: asid_t) -> findVSpaceForASID_ret {
-    let mut ret: findVSpaceForASID_ret = findVSpaceForASID_ret {
-        status: exception_t::EXCEPTION_FAULT,
-        vspace_root: None,
-        lookup_fault: None,
-    };
-
-    let poolPtr =  unsafe {
-        riscvKSASIDTable[asid >> asidLowBits]
-    };
-    if poolPtr as usize == 0 {
-        ret.lookup_fault = Some(lookup_fault_t::new_root_invalid());
-        ret.vspace_root = None;
-        ret.status = exception_t::EXCEPTION_LOOKUP_FAULT;
-        return ret;
-    }
-    let vspace_root = unsafe {
-        (*poolPtr).array[asid & MASK!(asidLowBits)]
-    };
-    if vspace_root as usize == 0 {
-        ret.lookup_fault = Some(lookup_fault_t::new_root_invalid());
-        ret.vspace_root = None;
-        ret.status = exception_t::EXCEPTION_LOOKUP_FAULT;
-        return ret;
-    }
-    ret.vspace_root = Some(vspace_root);
-    ret.status = exception_t::EXCEPTION_NONE;
-    // vspace_root0xffffffc17fec1000
-    return ret;
+pub fn find_vspace_for_asid(asid: Asid) -> Result<&'static mut pte_t, lookup_fault_t> {
+    get_asid_pool_by_index(asid.pool_index())
+        .and_then(|pool| pool.get_vspace_by_asid(asid))
+        .ok_or(lookup_fault_t::new_root_invalid())
 }
 
+/// 与`find_vspace_for_asid`相同，但`asid`为未经检查的`asid_t`，超出范围时同样返回`lookup_fault_invalid_root`
+/// 
+/// Find the root page table of a raw asid.
+#[inline]
+pub fn find_vspace_for_raw_asid(asid: asid_t) -> Result<&'static mut pte_t, lookup_fault_t> {
+    Asid::new(asid).ok_or(lookup_fault_t::new_root_invalid()).and_then(find_vspace_for_asid)
+}
+
+/// `find_vspace_for_asid`的`C`接口，将结果填入`findVSpaceForASID_ret`
+/// 
+/// C shim of `find_vspace_for_asid`.
+// `lookup_fault_t`只包含一个`[usize; 2]`，与`C`中的`lookup_fault_t`布局相同，只是没有标注`repr(C)`
+#[allow(improper_ctypes_definitions)]
 #[no_mangle]
-pub fn findVSpaceForASID(_asid: asid_t) -> findVSpaceForASID_ret {
-    panic!("should not be invoked!")
+pub extern "C" fn findVSpaceForASID(asid: asid_t) -> findVSpaceForASID_ret {
+    match find_vspace_for_raw_asid(asid) {
+        Ok(vspace_root) => findVSpaceForASID_ret {
+            status: exception_t::EXCEPTION_NONE,
+            vspace_root: vspace_root as *mut pte_t,
+            lookup_fault: lookup_fault_t::default(),
+        },
+        Err(lookup_fault) => findVSpaceForASID_ret {
+            status: exception_t::EXCEPTION_LOOKUP_FAULT,
+            vspace_root: core::ptr::null_mut(),
+            lookup_fault,
+        },
+    }
 }
 
-///清除`TLB`中对应`asid`的项
+///清除`TLB`中对应`asid`的项，硬件不支持`asid`时清空整个`TLB`，
+/// 该`asid`在当前代中没有硬件`asid`时`TLB`中不会有它的项，无需清除，
+/// 多核下还会清除激活过该`asid`的其他核上的项
 #[inline]
-fn hwASIDFlush(asid: asid_t) {
+pub(crate) fn hwASIDFlush(asid: asid_t) {
     unsafe {
-        asm!("sfence.vma x0, {0}",in(reg) asid);
+        match hw_asid(asid) {
+            Some(hw_asid) => asm!("sfence.vma x0, {0}",in(reg) hw_asid),
+            None if get_hw_asid_bits() == 0 => asm!("sfence.vma"),
+            None => {}
+        }
     }
+    #[cfg(feature = "ENABLE_SMP")]
+    remote_sfence(None, 0, 0, Some(asid));
 }
 ///在`riscvKSASIDTable`中删除对应的`asid pool`，
 /// 并设置新使用的页表为`default_vspace_cap`提供的页表
 /// 
 /// delete the asid pool which contains many asids.
-pub fn delete_asid_pool(asid_base: asid_t, pool: *mut asid_pool_t, default_vspace_cap: &cap_t) -> Result<(), lookup_fault_t> {
+pub fn delete_asid_pool(asid_base: Asid, pool: &asid_pool_t, default_vspace_cap: &cap_t) -> Result<(), lookup_fault_t> {
     unsafe {
-        if riscvKSASIDTable[asid_base >> asidLowBits] == pool {
-            riscvKSASIDTable[asid_base >> asidLowBits] = 0 as *mut asid_pool_t;
+        if core::ptr::eq(riscvKSASIDTable[asid_base.pool_index()], pool) {
+            for (index, vspace) in pool.array.iter().enumerate() {
+                if !vspace.is_null() {
+                    hwASIDFlush(asid_base.get() + index);
+                    release_hw_asid(asid_base.get() + index);
+                }
+            }
+            (*core::ptr::addr_of_mut!(riscvKSASIDMeta[asid_base.pool_index()])).array = [asid_meta_t::default(); BIT!(asidLowBits)];
+            riscvKSASIDTable[asid_base.pool_index()] = 0 as *mut asid_pool_t;
             set_vm_root(default_vspace_cap)
         } else {
             Ok(())
@@ -136,12 +416,16 @@ pub fn delete_asid_pool(asid_base: asid_t, pool: *mut asid_pool_t, default_vspac
 /// 并设置新使用的页表为`default_vspace_cap`提供的页表
 /// 
 /// delete the asid from asid pool.
-pub fn delete_asid(asid: asid_t, vspace: *mut pte_t, default_vspace_cap: &cap_t) -> Result<(), lookup_fault_t> {
+pub fn delete_asid(asid: Asid, vspace: *mut pte_t, default_vspace_cap: &cap_t) -> Result<(), lookup_fault_t> {
     unsafe {
-        let poolPtr = riscvKSASIDTable[asid >> asidLowBits];
-        if poolPtr as usize != 0 && (*poolPtr).array[asid & MASK!(asidLowBits)] == vspace {
-            hwASIDFlush(asid);
-            (*poolPtr).array[asid & MASK!(asidLowBits)] = 0 as *mut pte_t;
+        let poolPtr = riscvKSASIDTable[asid.pool_index()];
+        if poolPtr as usize != 0 && (*poolPtr).array[asid.slot_index()] == vspace {
+            hwASIDFlush(asid.get());
+            release_hw_asid(asid.get());
+            if let Some(meta) = get_asid_meta(asid.get()) {
+                meta.clear();
+            }
+            (*poolPtr).array[asid.slot_index()] = 0 as *mut pte_t;
             set_vm_root(&default_vspace_cap)
         } else {
             Ok(())
@@ -0,0 +1,31 @@
+//! 与页表级数相关的配置：默认使用`sel4_common`中`Sv39`的配置，
+//! 开启`RISCV_SV48`或`RISCV_SV57`时改用四级或五级页表，`PSpace`的起始地址随之下移，
+//! `PPTR_TOP`和`KERNEL_ELF_BASE`在各模式中保持不变
+//! 
+//! 内核中其他依赖`PPTR_BASE`的部分需要使用这里的配置
+pub use sel4_common::sel4_config::{KERNEL_ELF_BASE, KERNEL_ELF_PADDR_BASE, PADDR_BASE, PPTR_TOP, PT_INDEX_BITS};
+
+#[cfg(all(feature = "RISCV_SV48", feature = "RISCV_SV57"))]
+compile_error!("features `RISCV_SV48` and `RISCV_SV57` are mutually exclusive");
+
+#[cfg(not(any(feature = "RISCV_SV48", feature = "RISCV_SV57")))]
+pub use sel4_common::sel4_config::{CONFIG_PT_LEVELS, PPTR_BASE};
+
+/// `Sv48`使用四级页表
+#[cfg(feature = "RISCV_SV48")]
+pub const CONFIG_PT_LEVELS: usize = 4;
+
+/// `Sv48`中`PSpace`从内核地址空间的起点`2^64 - 2^47`开始
+#[cfg(feature = "RISCV_SV48")]
+pub const PPTR_BASE: usize = 0xFFFF800000000000;
+
+/// `Sv57`使用五级页表
+#[cfg(feature = "RISCV_SV57")]
+pub const CONFIG_PT_LEVELS: usize = 5;
+
+/// `Sv57`中`PSpace`从内核地址空间的起点`2^64 - 2^56`开始
+#[cfg(feature = "RISCV_SV57")]
+pub const PPTR_BASE: usize = 0xFF00000000000000;
+
+pub const PPTR_BASE_OFFSET: usize = PPTR_BASE - PADDR_BASE;
+pub const PADDR_TOP: usize = PPTR_TOP - PPTR_BASE_OFFSET;
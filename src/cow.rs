@@ -0,0 +1,52 @@
+//! 基于页表项`RSW`位实现的写时复制（`copy-on-write`）
+use riscv::register::scause::Exception;
+use sel4_common::sel4_config::seL4_PageBits;
+
+use super::allocator::FrameAllocator;
+use super::interface::translate;
+use super::pte::{pte_t, PTE_SW_COW, PTE_SW_SHARED};
+use super::asid::asid_t;
+use super::satp::sfence_vma;
+use super::structures::{vptr_t, VSpaceError};
+use super::utils::{paddr_to_pptr, pptr_to_paddr, RISCV_GET_LVL_PGSIZE};
+
+/// 判断一次缺页异常是否为对写时复制页面的写操作引起的，
+/// `fault_type`为`scause`中的异常号，`vaddr`为`stval`中的出错地址
+/// 
+/// Whether the page fault is a store to a copy-on-write page.
+pub fn is_cow_fault(vspace_root: &pte_t, vaddr: vptr_t, fault_type: usize) -> bool {
+    if Exception::from(fault_type) != Exception::StorePageFault {
+        return false;
+    }
+    match translate(vspace_root, vaddr) {
+        Ok(translation) => unsafe { (*translation.ptSlot).is_cow() },
+        Err(_) => false,
+    }
+}
+
+/// 处理对写时复制页面的写操作：通过`frame_alloc`分配新页面并复制原页面的内容，
+/// 然后让`vaddr`所在的页表项指向新页面并恢复`write`权限，最后只清除`asid`中该页面对应的`TLB`项
+/// 
+/// 原页面仍由其他共享者使用，其回收由调用者负责
+/// 
+/// Resolve a store fault on a copy-on-write page by copying the frame and restoring write permission.
+pub fn resolve_cow_fault<F: FrameAllocator>(vspace_root: &mut pte_t, asid: asid_t, vaddr: vptr_t,
+                                            frame_alloc: &mut F) -> Result<(), VSpaceError> {
+    let translation = translate(vspace_root, vaddr)?;
+    let slot = unsafe { &mut *translation.ptSlot };
+    if !slot.is_cow() {
+        return Err(VSpaceError::NotCopyOnWrite);
+    }
+    let frame = frame_alloc.alloc_frame(translation.page_size).ok_or(VSpaceError::OutOfMemory)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(paddr_to_pptr(slot.get_ppn() << seL4_PageBits) as *const u8, frame as *mut u8,
+                                       RISCV_GET_LVL_PGSIZE(translation.level));
+    }
+    let mut pte = *slot;
+    pte.set_ppn(pptr_to_paddr(frame) >> seL4_PageBits);
+    pte.set_sw(pte.get_sw() & !(PTE_SW_COW | PTE_SW_SHARED));
+    pte.set_write(1);
+    *slot = pte;
+    sfence_vma(Some(vaddr), Some(asid));
+    Ok(())
+}
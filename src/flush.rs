@@ -0,0 +1,158 @@
+//! 批量清除`TLB`：修改多个页表项时先记录受影响的`(asid, 地址范围)`，修改完成后统一清除
+use sel4_common::sel4_config::seL4_PageBits;
+
+use super::asid::asid_t;
+use super::satp::{sfence, sfence_range, sfence_vma, SFENCE_RANGE_MAX_PAGES};
+use super::structures::vptr_t;
+
+/// `FlushBatch`最多记录的范围数，超过后退化为清除整个`TLB`
+pub const FLUSH_BATCH_CAPACITY: usize = 16;
+
+/// 一段需要清除的范围，`len`为0表示整个`asid`，`asid`为`None`表示所有`asid`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FlushRange {
+    asid: Option<asid_t>,
+    vstart: vptr_t,
+    len: usize,
+}
+
+/// 收集页表修改所影响的`TLB`项，在`finish`或被`drop`时一次性清除
+/// 
+/// 相邻的范围会被合并；记录的页面总数超过`threshold`时，各范围改为按`asid`整体清除；
+/// 记录的范围超过`FLUSH_BATCH_CAPACITY`时改为清除整个`TLB`
+/// 
+/// Collects dirtied ranges while page tables are edited and flushes them once.
+#[derive(Debug)]
+pub struct FlushBatch {
+    ranges: [FlushRange; FLUSH_BATCH_CAPACITY],
+    count: usize,
+    pages: usize,
+    threshold: usize,
+    overflow: bool,
+}
+
+impl FlushBatch {
+    /// 使用默认阈值`SFENCE_RANGE_MAX_PAGES`
+    #[inline]
+    pub fn new() -> Self {
+        Self::with_threshold(SFENCE_RANGE_MAX_PAGES)
+    }
+
+    /// `threshold`为按地址清除的最大页面数
+    #[inline]
+    pub fn with_threshold(threshold: usize) -> Self {
+        FlushBatch {
+            ranges: [FlushRange { asid: None, vstart: 0, len: 0 }; FLUSH_BATCH_CAPACITY],
+            count: 0,
+            pages: 0,
+            threshold,
+            overflow: false,
+        }
+    }
+
+    /// 记录`asid`地址空间中`[vstart, vstart + len)`范围内的页表项被修改，`asid`的含义与`sfence_vma`相同
+    pub fn add_range(&mut self, asid: Option<asid_t>, vstart: vptr_t, len: usize) {
+        if len == 0 || self.overflow {
+            return;
+        }
+        for range in self.ranges[..self.count].iter_mut() {
+            if range.asid != asid {
+                continue;
+            }
+            if range.len == 0 {
+                return;
+            }
+            if range.vstart + range.len == vstart {
+                range.len += len;
+                self.pages += len >> seL4_PageBits;
+                return;
+            }
+            if vstart + len == range.vstart {
+                range.vstart = vstart;
+                range.len += len;
+                self.pages += len >> seL4_PageBits;
+                return;
+            }
+        }
+        self.push(FlushRange { asid, vstart, len });
+        self.pages += len >> seL4_PageBits;
+    }
+
+    /// 记录`asid`中的页表被删除或大范围修改，需要清除该`asid`的全部项
+    pub fn add_asid(&mut self, asid: Option<asid_t>) {
+        if self.overflow {
+            return;
+        }
+        let mut index = 0;
+        while index < self.count {
+            if self.ranges[index].asid == asid {
+                self.pages -= self.ranges[index].len >> seL4_PageBits;
+                self.count -= 1;
+                self.ranges[index] = self.ranges[self.count];
+            } else {
+                index += 1;
+            }
+        }
+        self.push(FlushRange { asid, vstart: 0, len: 0 });
+    }
+
+    #[inline]
+    fn push(&mut self, range: FlushRange) {
+        if self.count == FLUSH_BATCH_CAPACITY {
+            self.overflow = true;
+        } else {
+            self.ranges[self.count] = range;
+            self.count += 1;
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.count == 0 && !self.overflow
+    }
+
+    /// 清除已记录的所有范围并清空记录
+    /// 
+    /// Issue the fences for everything recorded so far.
+    pub fn flush(&mut self) {
+        if self.overflow {
+            sfence();
+        } else {
+            let by_asid = self.pages > self.threshold;
+            for (index, range) in self.ranges[..self.count].iter().enumerate() {
+                if by_asid && self.ranges[..index].iter().any(|prev| prev.asid == range.asid) {
+                    continue;
+                }
+                if range.len == 0 || by_asid {
+                    sfence_vma(None, range.asid);
+                } else {
+                    sfence_range(range.vstart, range.len, range.asid);
+                }
+            }
+        }
+        self.count = 0;
+        self.pages = 0;
+        self.overflow = false;
+    }
+
+    /// 结束本次批量修改，清除所有记录的范围
+    #[inline]
+    pub fn finish(mut self) {
+        self.flush();
+    }
+}
+
+impl Default for FlushBatch {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FlushBatch {
+    fn drop(&mut self) {
+        if !self.is_empty() {
+            self.flush();
+        }
+    }
+}
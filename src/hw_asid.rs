@@ -0,0 +1,216 @@
+//! `seL4 asid`到硬件`asid`的映射，采用与`Linux`相同的按代（`generation`）分配的方式，
+//! 硬件`asid`用尽时进入下一代并清空所有核的`TLB`
+use sel4_common::sel4_config::{asidInvalid, wordBits, wordRadix, ASID_BITS, CONFIG_MAX_NUM_NODES};
+use sel4_common::utils::cpu_id;
+use sel4_common::{BIT, MASK};
+
+use super::asid::{asid_t, get_asid_meta, riscvKSASIDMeta};
+use super::satp::{get_hw_asid_bits, sfence};
+
+/// 当前代中已经被分配的硬件`asid`的位图
+static mut hwASIDBitmap: [usize; BIT!(ASID_BITS - wordRadix)] = [0; BIT!(ASID_BITS - wordRadix)];
+
+/// 当前的代数，按`1 << hwASIDBits`递增，为0表示尚未初始化
+static mut hwASIDGeneration: u32 = 0;
+
+/// 每个核上正在使用的`seL4 asid`，使用内核页表时为`asidInvalid`，进入下一代时这些`asid`会保留原来的硬件`asid`
+static mut hwASIDActive: [asid_t; CONFIG_MAX_NUM_NODES] = [asidInvalid; CONFIG_MAX_NUM_NODES];
+
+/// 每个核的`satp`中正在使用的硬件`asid`，使用内核页表时为0；`asid`被删除后其他核可能仍在使用原来的硬件`asid`，
+/// 这些硬件`asid`在对应的核切换到其他地址空间之前不能分配给其他`asid`
+static mut hwASIDInUse: [usize; CONFIG_MAX_NUM_NODES] = [0; CONFIG_MAX_NUM_NODES];
+
+/// 硬件`asid`是否足以按代分配：新的一代会保留各核正在使用的硬件`asid`以及内核使用的0，
+/// 因此硬件`asid`的数量必须多于核数加一，否则与`Linux`一样视为不支持`asid`
+#[inline]
+pub(crate) fn enough_hw_asids(bits: usize) -> bool {
+    bits >= ASID_BITS || BIT!(bits) > CONFIG_MAX_NUM_NODES + 1
+}
+
+/// 是否需要经过按代分配的映射，硬件`asid`位数足以表示所有`seL4 asid`时直接使用`seL4 asid`
+#[inline]
+fn use_generation(bits: usize) -> bool {
+    bits != 0 && bits < ASID_BITS && enough_hw_asids(bits)
+}
+
+/// 每个`seL4 asid`当前对应的上下文保存在`asid_meta_t`中，高位为分配时的代数，
+/// 低`hwASIDBits`位为硬件`asid`，为0表示从未分配
+#[inline]
+fn get_context(asid: asid_t) -> u32 {
+    get_asid_meta(asid).map_or(0, |meta| meta.get_hw_context())
+}
+
+/// 设置`asid`的上下文，同时将核掩码重置为`hart_mask`，新的上下文之前的`TLB`项都已被清除
+#[inline]
+fn set_context(asid: asid_t, context: u32, hart_mask: usize) {
+    if let Some(meta) = get_asid_meta(asid) {
+        meta.set_hw_context(context);
+        meta.set_hart_mask(hart_mask);
+    }
+}
+
+#[inline]
+unsafe fn bitmap_set(hw_asid: usize) {
+    hwASIDBitmap[hw_asid >> wordRadix] |= BIT!(hw_asid & MASK!(wordRadix));
+}
+
+#[inline]
+unsafe fn bitmap_clear(hw_asid: usize) {
+    hwASIDBitmap[hw_asid >> wordRadix] &= !BIT!(hw_asid & MASK!(wordRadix));
+}
+
+/// 在位图中寻找一个空闲的硬件`asid`，硬件`asid`0保留给内核
+unsafe fn bitmap_find_free(bits: usize) -> Option<usize> {
+    let bitmap = &*core::ptr::addr_of!(hwASIDBitmap);
+    for (word, used) in bitmap.iter().enumerate().take(BIT!(bits).div_ceil(wordBits)) {
+        let free = !used;
+        if free != 0 {
+            let hw_asid = (word << wordRadix) + free.trailing_zeros() as usize;
+            return if hw_asid < BIT!(bits) { Some(hw_asid) } else { None };
+        }
+    }
+    None
+}
+
+/// 进入下一代：清空位图，各核正在使用的硬件`asid`保持被占用，对应的`asid`保留原来的硬件`asid`，然后清空所有核的`TLB`
+unsafe fn new_generation(bits: usize) {
+    hwASIDGeneration = match hwASIDGeneration.checked_add(1 << bits) {
+        Some(generation) => generation,
+        None => {
+            // 代数溢出后旧的上下文可能被误认为属于当前代，全部作废，各核正在使用的`asid`在下面重新设置
+            for pool in (*core::ptr::addr_of_mut!(riscvKSASIDMeta)).iter_mut() {
+                for meta in pool.array.iter_mut() {
+                    meta.set_hw_context(0);
+                }
+            }
+            1 << bits
+        }
+    };
+    hwASIDBitmap = [0; BIT!(ASID_BITS - wordRadix)];
+    bitmap_set(0);
+    for (cpu, &asid) in (*core::ptr::addr_of!(hwASIDActive)).iter().enumerate() {
+        // 已被删除的`asid`不再记录在`hwASIDActive`中，但其他核可能仍在使用它的硬件`asid`
+        let hw_asid = hwASIDInUse[cpu];
+        bitmap_set(hw_asid);
+        if asid != asidInvalid {
+            // 同一个`asid`可能同时在多个核上使用，保留先前核的掩码
+            let hart_mask = if get_context(asid) & !(MASK!(bits) as u32) == hwASIDGeneration {
+                get_asid_meta(asid).map_or(0, |meta| meta.get_hart_mask())
+            } else {
+                0
+            };
+            set_context(asid, hwASIDGeneration | hw_asid as u32, hart_mask | BIT!(cpu));
+        }
+    }
+    sfence();
+}
+
+/// 获得`asid`在当前代中对应的硬件`asid`，尚未分配时返回`None`，用于清除`TLB`
+/// 
+/// 硬件不支持`asid`时总是返回`None`
+/// 
+/// Get the hardware asid currently assigned to `asid`.
+pub fn hw_asid(asid: asid_t) -> Option<usize> {
+    let bits = get_hw_asid_bits();
+    if !use_generation(bits) {
+        return if bits == 0 { None } else { Some(asid) };
+    }
+    unsafe {
+        let context = get_context(asid);
+        if hwASIDGeneration != 0 && context & !(MASK!(bits) as u32) == hwASIDGeneration {
+            Some(context as usize & MASK!(bits))
+        } else {
+            None
+        }
+    }
+}
+
+/// 为即将在本核上使用的`asid`分配硬件`asid`，必要时进入下一代，用于`setVSpaceRoot`
+/// 
+/// 同时在`asid_meta_t`的核掩码中记录本核，硬件不支持`asid`时返回`None`
+/// 
+/// Assign a hardware asid to `asid` before it's written into satp.
+pub fn assign_hw_asid(asid: asid_t) -> Option<usize> {
+    let bits = get_hw_asid_bits();
+    if !use_generation(bits) {
+        if let Some(meta) = get_asid_meta(asid).filter(|_| asid != asidInvalid) {
+            meta.add_hart(cpu_id());
+        }
+        unsafe {
+            hwASIDActive[cpu_id()] = asid;
+        }
+        return if bits == 0 { None } else { Some(asid) };
+    }
+    if asid == asidInvalid {
+        unsafe {
+            hwASIDActive[cpu_id()] = asidInvalid;
+            hwASIDInUse[cpu_id()] = 0;
+        }
+        return Some(0);
+    }
+    unsafe {
+        if hwASIDGeneration == 0 {
+            new_generation(bits);
+        }
+        let hw_asid = match hw_asid(asid) {
+            Some(hw_asid) => hw_asid,
+            None => {
+                let hw_asid = match bitmap_find_free(bits) {
+                    Some(hw_asid) => hw_asid,
+                    None => {
+                        new_generation(bits);
+                        // 新的一代中保留的硬件`asid`不会多于核数，一定能找到空闲的硬件`asid`
+                        bitmap_find_free(bits).unwrap()
+                    }
+                };
+                bitmap_set(hw_asid);
+                set_context(asid, hwASIDGeneration | hw_asid as u32, 0);
+                hw_asid
+            }
+        };
+        if let Some(meta) = get_asid_meta(asid) {
+            meta.add_hart(cpu_id());
+        }
+        hwASIDActive[cpu_id()] = asid;
+        hwASIDInUse[cpu_id()] = hw_asid;
+        Some(hw_asid)
+    }
+}
+
+/// 释放`asid`在当前代中占用的硬件`asid`，在删除`asid`时调用，调用者负责清除对应的`TLB`项
+/// 
+/// 其他核的`satp`中仍是该硬件`asid`时不会释放，直到这些核切换到其他地址空间后的下一代才会被回收
+/// 
+/// Release the hardware asid of a deleted `asid`.
+pub fn release_hw_asid(asid: asid_t) {
+    unsafe {
+        for active in (*core::ptr::addr_of_mut!(hwASIDActive)).iter_mut().filter(|active| **active == asid) {
+            *active = asidInvalid;
+        }
+    }
+    let bits = get_hw_asid_bits();
+    if !use_generation(bits) {
+        return;
+    }
+    unsafe {
+        if let Some(hw_asid) = hw_asid(asid) {
+            let in_use = (*core::ptr::addr_of!(hwASIDInUse))
+                .iter()
+                .enumerate()
+                .any(|(cpu, &in_use)| cpu != cpu_id() && in_use == hw_asid);
+            if !in_use {
+                bitmap_clear(hw_asid);
+            }
+        }
+    }
+    set_context(asid, 0, 0);
+}
+
+/// 获得本核当前使用的`seL4 asid`，使用内核页表时返回`asidInvalid`；
+/// 与`satp_t::get_asid`不同，后者是写入`satp`的硬件`asid`
+/// 
+/// The seL4 asid whose vspace is active on this hart.
+#[inline]
+pub fn current_asid() -> asid_t {
+    unsafe { hwASIDActive[cpu_id()] }
+}
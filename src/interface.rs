@@ -1,28 +1,46 @@
 // use crate::{common::{sel4_config::*, structures::exception_t, utils::{convert_to_mut_type_ref, pageBitsForSize}, fault::*}, BIT, ROUND_DOWN};
 use sel4_cspace::interface::{cap_t, CapTag};
 use core::intrinsics::unlikely;
-use sel4_common::{BIT, ROUND_DOWN};
+use sel4_common::{BIT, MASK, ROUND_DOWN};
 use sel4_common::fault::lookup_fault_t;
-use sel4_common::sel4_config::{KERNEL_ELF_BASE, KERNEL_ELF_PADDR_BASE, PADDR_BASE, PPTR_BASE, PPTR_BASE_OFFSET, PPTR_TOP, PT_INDEX_BITS, seL4_PageBits};
-use sel4_common::structures::exception_t;
+use sel4_common::sel4_config::seL4_PageBits;
+use super::config::{CONFIG_PT_LEVELS, KERNEL_ELF_BASE, KERNEL_ELF_PADDR_BASE, PADDR_BASE, PPTR_BASE, PPTR_BASE_OFFSET, PPTR_TOP, PT_INDEX_BITS};
 use sel4_common::utils::{convert_to_mut_type_ref, pageBitsForSize};
-use super::pte::pte_t;
-use super::utils::{RISCV_GET_PT_INDEX, RISCV_GET_LVL_PGSIZE, RISCV_GET_LVL_PGSIZE_BITS, kpptr_to_paddr};
+use super::pte::{pte_t, PteFlags, Translation, PTE_SW_COW, PTE_SW_SHARED};
+use super::iter::VSpaceIter;
+use super::utils::{RISCV_GET_PT_INDEX, RISCV_GET_LVL_PGSIZE, RISCV_GET_LVL_PGSIZE_BITS, RISCV_USER_ROOT_ENTRIES, kpptr_to_paddr, checkVPAlignment};
+use super::allocator::{FrameAllocator, PageTableAllocator, PageTableDeallocator};
+use super::flush::FlushBatch;
 
-use super::{satp::{setVSpaceRoot, sfence}, asid::{find_vspace_for_asid, asid_t}, utils::pptr_to_paddr, structures::{vptr_t, pptr_t}};
+use super::vm_rights::{RISCVGetReadFromVMRights, RISCVGetWriteFromVMRights};
+use super::{satp::{setVSpaceRoot, sfence_vma, current_vspace, probe_asid_bits}, asid::{find_vspace_for_raw_asid, get_asid_meta, add_mapping_count, sub_mapping_count, asid_t}, utils::{pptr_to_paddr, paddr_to_pptr},
+            structures::{vptr_t, pptr_t, paddr_t, vm_attributes_t, VSpaceError, ClonePolicy}};
 
-///页表采用`SV39`，该变量是内核使用的页表的根页表（一级页表）
+///页表采用`Sv39`、`Sv48`或`Sv57`，由`CONFIG_PT_LEVELS`决定，该变量是内核使用的页表的根页表（一级页表）
 #[no_mangle]
 #[link_section = ".page_table"]
 pub static mut kernel_root_pageTable: [pte_t; BIT!(PT_INDEX_BITS)] =
     [pte_t { words: [0] }; BIT!(PT_INDEX_BITS)];
 
-///内核使用的二级页表
+///内核使用的`2MB`页表，用于映射`KERNEL ELF`所在的`1GB`，`Sv39`中为二级页表
 #[no_mangle]
 #[link_section = ".page_table"]
 pub static mut kernel_image_level2_pt: [pte_t; BIT!(PT_INDEX_BITS)] =
     [pte_t { words: [0] }; BIT!(PT_INDEX_BITS)];
 
+/// `1GB`页面所在的页表级数，`Sv39`中为根页表，`Sv48`、`Sv57`中依次下移一级
+const KERNEL_GIGA_LEVEL: usize = CONFIG_PT_LEVELS - 3;
+
+/// 内核窗口中根页表与`1GB`页表之间的中间页表数量，
+/// `PSpace`的首尾两端未按根页表项的大小对齐时，各需要一条中间页表链
+const KERNEL_WINDOW_PTS: usize = 2 * KERNEL_GIGA_LEVEL;
+
+///`Sv48`、`Sv57`中内核窗口使用的中间页表，`Sv39`中不需要
+#[no_mangle]
+#[link_section = ".page_table"]
+pub static mut kernel_window_pts: [[pte_t; BIT!(PT_INDEX_BITS)]; KERNEL_WINDOW_PTS] =
+    [[pte_t { words: [0] }; BIT!(PT_INDEX_BITS)]; KERNEL_WINDOW_PTS];
+
 /// 构建`reL4`的内核页表,主要完成了`PSpace`和`KERNEL ELF`两段虚拟地址空间的映射 
 /// 
 /// 其中`PSpace`是对整个物理地址空间的线性映射，`KERNEL ELF`是对内核代码的再一次映射
@@ -69,6 +87,8 @@ pub static mut kernel_image_level2_pt: [pte_t; BIT!(PT_INDEX_BITS)] =
 ///                      virtual address space                          physical address space
 /// ```
 /// 
+/// `Sv48`、`Sv57`中`1GB`页表不再是根页表，`PSpace`中对齐的部分直接使用更大的页面映射，
+/// 首尾未对齐的部分通过`kernel_window_pts`中的中间页表下降到`1GB`页面
 #[no_mangle]
 pub fn rust_map_kernel_window() {
     // 内核地址空间中直接映射物理地址空间的起始地址
@@ -77,43 +97,73 @@ pub fn rust_map_kernel_window() {
     // 物理地址空间的起始地址
     let mut paddr = PADDR_BASE;
 
-    // 物理地址到内核地址空间的直接映射，用`1GB`大页的方式映射
+    // 已经使用的`kernel_window_pts`的数量
+    let mut used_pts = 0;
+
+    // 物理地址到内核地址空间的直接映射，用不小于`1GB`的大页的方式映射
     while pptr < PPTR_TOP {
+        let level = largest_page_level(pptr, paddr, PPTR_TOP - pptr);
+        assert!(RISCV_GET_LVL_PGSIZE_BITS(level) >= RISCV_GET_LVL_PGSIZE_BITS(KERNEL_GIGA_LEVEL));
         unsafe {
-            kernel_root_pageTable[RISCV_GET_PT_INDEX(pptr, 0)] = pte_t::pte_next(paddr, true);
+            *kernel_window_slot(pptr, level, &mut used_pts).unwrap() = pte_t::pte_next(paddr, true);
         }
-        pptr += RISCV_GET_LVL_PGSIZE(0);
-        paddr += RISCV_GET_LVL_PGSIZE(0);
+        pptr += RISCV_GET_LVL_PGSIZE(level);
+        paddr += RISCV_GET_LVL_PGSIZE(level);
     }
-    pptr = ROUND_DOWN!(KERNEL_ELF_BASE, RISCV_GET_LVL_PGSIZE_BITS(0));
-    paddr = ROUND_DOWN!(KERNEL_ELF_PADDR_BASE, RISCV_GET_LVL_PGSIZE_BITS(0));
+    pptr = ROUND_DOWN!(KERNEL_ELF_BASE, RISCV_GET_LVL_PGSIZE_BITS(KERNEL_GIGA_LEVEL));
+    paddr = ROUND_DOWN!(KERNEL_ELF_PADDR_BASE, RISCV_GET_LVL_PGSIZE_BITS(KERNEL_GIGA_LEVEL));
 
-    // 将根页表`KERNEL_ELF_PADDR_BASE`和`KERNEL_ELF_BASE`处的页表项改为使用`kernel_image_level2_pt`映射
+    // 将`KERNEL_ELF_PADDR_BASE`和`KERNEL_ELF_BASE`所在的`1GB`页表项改为使用`kernel_image_level2_pt`映射，
+    // 前者位于更大的页面中时保持不变，两者映射到相同的物理地址
     unsafe {
-        kernel_root_pageTable[RISCV_GET_PT_INDEX(KERNEL_ELF_PADDR_BASE + PPTR_BASE_OFFSET, 0)] =
-            pte_t::pte_next(
-                kpptr_to_paddr(kernel_image_level2_pt.as_ptr() as usize),
-                false,
-            );
-        kernel_root_pageTable[RISCV_GET_PT_INDEX(pptr, 0)] = pte_t::pte_next(
-            kpptr_to_paddr(kernel_image_level2_pt.as_ptr() as usize),
-            false,
-        );
+        let image_pt = pte_t::pte_next(kpptr_to_paddr(kernel_image_level2_pt.as_ptr() as usize), false);
+        if let Some(slot) = kernel_window_slot(KERNEL_ELF_PADDR_BASE + PPTR_BASE_OFFSET, KERNEL_GIGA_LEVEL, &mut used_pts) {
+            *slot = image_pt;
+        }
+        *kernel_window_slot(pptr, KERNEL_GIGA_LEVEL, &mut used_pts).unwrap() = image_pt;
     }
 
     let mut index = 0;
     // 做了 `0xFFFF_FFFF_8400_0000(KERNEL_ELF_BASE)~0xFFFF_FFFF_C4000_0000(KDEV_BASE)`到`0x8400_0000~0xC400_0000`的地址映射。
-    while pptr < PPTR_TOP + RISCV_GET_LVL_PGSIZE(0) {
+    while pptr < PPTR_TOP + RISCV_GET_LVL_PGSIZE(KERNEL_GIGA_LEVEL) {
         unsafe {
             kernel_image_level2_pt[index] = pte_t::pte_next(paddr, true);
         }
-        pptr += RISCV_GET_LVL_PGSIZE(1);
-        paddr += RISCV_GET_LVL_PGSIZE(1);
+        pptr += RISCV_GET_LVL_PGSIZE(KERNEL_GIGA_LEVEL + 1);
+        paddr += RISCV_GET_LVL_PGSIZE(KERNEL_GIGA_LEVEL + 1);
         index += 1;
     }
 }
 
-/// 激活内核页表，将`satp`的值设置为内核页表根页表地址
+/// 在内核页表中找到`vptr`在第`level`级的页表项，缺失的中间页表从`kernel_window_pts`中依次取用
+/// 
+/// 途中遇到已经映射的大页时返回`None`
+unsafe fn kernel_window_slot(vptr: vptr_t, level: usize, used_pts: &mut usize) -> Option<&'static mut pte_t> {
+    let mut pt = core::ptr::addr_of_mut!(kernel_root_pageTable) as *mut pte_t;
+    for i in 0..level {
+        let slot = &mut *pt.add(RISCV_GET_PT_INDEX(vptr, i));
+        if slot.get_vaild() == 0 {
+            // `Sv39`中`KERNEL_WINDOW_PTS`为0，不会走到这里
+            let next = (*core::ptr::addr_of_mut!(kernel_window_pts))
+                .get_mut(*used_pts)
+                .expect("kernel_window_pts exhausted")
+                .as_mut_ptr();
+            *used_pts += 1;
+            *slot = pte_t::pte_next(kpptr_to_paddr(next as usize), false);
+        } else if !slot.is_pte_table() {
+            return None;
+        }
+        // 此时`PSpace`尚未建立，只能通过`kernel_window_pts`的内核地址访问中间页表
+        let paddr = slot.get_ppn() << seL4_PageBits;
+        pt = (0..*used_pts)
+            .map(|index| core::ptr::addr_of_mut!(kernel_window_pts[index]) as *mut pte_t)
+            .find(|&next| kpptr_to_paddr(next as usize) == paddr)
+            .unwrap();
+    }
+    Some(&mut *pt.add(RISCV_GET_PT_INDEX(vptr, level)))
+}
+
+/// 激活内核页表，将`satp`的值设置为内核页表根页表地址，开启分页之后探测硬件支持的`asid`位数
 /// 
 /// Activate kernel vspace, assign kernel root page table's value to satp.
 #[inline]
@@ -121,6 +171,15 @@ pub fn activate_kernel_vspace() {
     unsafe {
         setVSpaceRoot(kpptr_to_paddr(kernel_root_pageTable.as_ptr() as usize), 0);
     }
+    probe_asid_bits();
+}
+
+/// 判断本核当前使用的是否为内核页表`kernel_root_pageTable`
+/// 
+/// Check whether satp currently points at the kernel root page table.
+#[inline]
+pub fn is_kernel_vspace_active() -> bool {
+    unsafe { current_vspace().get_root_paddr() == kpptr_to_paddr(kernel_root_pageTable.as_ptr() as usize) }
 }
 
 /// 拷贝内核页表到新给出的页表基地址`Lvl1pt`，当创建一个进程的时候，会拷贝一个新的页表给新创建的进程，新的页表中包含内核地址空间
@@ -129,7 +188,12 @@ pub fn activate_kernel_vspace() {
 /// when create a new process, a new page table will be alloced to the new process.
 #[no_mangle]
 pub fn copyGlobalMappings(Lvl1pt: usize) {
-    let mut i: usize = RISCV_GET_PT_INDEX(0x80000000, 0);
+    // `Sv39`中从`0x80000000`所在的根页表项开始拷贝，`Sv48`、`Sv57`中该地址与用户地址空间共用第0项，
+    // 只拷贝内核地址空间对应的根页表项
+    let mut i: usize = match RISCV_GET_PT_INDEX(0x80000000, 0) {
+        0 => RISCV_USER_ROOT_ENTRIES(),
+        index => index,
+    };
     while i < BIT!(PT_INDEX_BITS) {
         unsafe {
             let newLvl1pt = (Lvl1pt + i * 8) as *mut usize;
@@ -151,20 +215,16 @@ pub fn set_vm_root(vspace_root: &cap_t) -> Result<(), lookup_fault_t> {
     }
     let lvl1pt = convert_to_mut_type_ref::<pte_t>(vspace_root.get_pt_base_ptr());
     let asid = vspace_root.get_pt_mapped_asid();
-    let find_ret = find_vspace_for_asid(asid);
-    let mut ret = Ok(());
-    if unlikely(
-        find_ret.status != exception_t::EXCEPTION_NONE || find_ret.vspace_root.is_none() || find_ret.vspace_root.unwrap() != lvl1pt,
-    ) {
+    let find_ret = find_vspace_for_raw_asid(asid);
+    if unlikely(find_ret.as_ref().map_or(true, |vspace_root| !core::ptr::eq(*vspace_root, lvl1pt))) {
+        // 查找失败时不能再用该`asid`切换，否则会为其分配硬件`asid`
         unsafe {
-            if let Some(lookup_fault) = find_ret.lookup_fault {
-                ret = Err(lookup_fault);
-            }
             setVSpaceRoot(kpptr_to_paddr(kernel_root_pageTable.as_ptr() as usize), 0);
         }
+        return find_ret.map(|_| ());
     }
     setVSpaceRoot(pptr_to_paddr(lvl1pt as *mut pte_t as usize), asid);
-    ret
+    Ok(())
 }
 
 /// 清除页表中对应的页表项。
@@ -176,12 +236,9 @@ pub fn set_vm_root(vspace_root: &cap_t) -> Result<(), lookup_fault_t> {
 /// `pptr`:分配的页面对应的虚拟地址(frame_base_ptr)
 #[no_mangle]
 pub fn unmapPage(page_size: usize, asid: asid_t, vptr: vptr_t, pptr: pptr_t) -> Result<(), lookup_fault_t> {
-    let find_ret = find_vspace_for_asid(asid);
-    if find_ret.status != exception_t::EXCEPTION_NONE {
-        return Err(find_ret.lookup_fault.unwrap());
-    }
+    let vspace_root = find_vspace_for_raw_asid(asid)?;
 
-    let lu_ret = unsafe {(*find_ret.vspace_root.unwrap()).lookup_pt_slot(vptr)};
+    let lu_ret = vspace_root.lookup_pt_slot(vptr);
 
     if lu_ret.ptBitsLeft != pageBitsForSize(page_size) {
         return Ok(());
@@ -196,7 +253,351 @@ pub fn unmapPage(page_size: usize, asid: asid_t, vptr: vptr_t, pptr: pptr_t) ->
     unsafe {
         let slot = lu_ret.ptSlot as *mut usize;
         *slot = 0;
-        sfence();
     }
+    sub_mapping_count(asid, 1);
+    sfence_vma(Some(vptr), Some(asid));
+    Ok(())
+}
+
+/// 以`vspace_root`为根页表，通过软件遍历页表完成虚拟地址`vptr`的地址翻译
+/// 
+/// 返回叶子节点对应的物理地址、页面大小、权限位以及存放槽，
+/// 如果在第`N`级遇到无效的页表项，则返回`VSpaceError::NotMapped { level: N }`
+/// 
+/// Walk the page table rooted at `vspace_root` and translate `vptr`.
+pub fn translate(vspace_root: &pte_t, vptr: vptr_t) -> Result<Translation, VSpaceError> {
+    let mut pt = vspace_root as *const pte_t as *mut pte_t;
+    let mut level = 0;
+    loop {
+        let ptSlot = unsafe { pt.add(RISCV_GET_PT_INDEX(vptr, level)) };
+        let pte = unsafe { &*ptSlot };
+        if pte.get_vaild() == 0 {
+            return Err(VSpaceError::NotMapped { level });
+        }
+        if !pte.is_pte_table() {
+            let page_bits = RISCV_GET_LVL_PGSIZE_BITS(level);
+            return Ok(Translation {
+                paddr: (pte.get_ppn() << seL4_PageBits) + (vptr & MASK!(page_bits)),
+                level,
+                page_size: CONFIG_PT_LEVELS - 1 - level,
+                flags: PteFlags::from_pte(pte),
+                ptSlot,
+            });
+        }
+        // 最后一级页表中的页表项只能是叶子节点
+        if level == CONFIG_PT_LEVELS - 1 {
+            return Err(VSpaceError::NotMapped { level });
+        }
+        pt = pte.get_pte_from_ppn_mut() as *mut pte_t;
+        level += 1;
+    }
+}
+
+/// 在以`vspace_root`为根的页表中建立`vptr`到`paddr`的映射，页面大小由`page_size`给出，
+/// `asid`为`vspace_root`所属的`asid`，用于记录映射的页面数和清除`TLB`
+/// 
+/// 所需的各级中间页表必须已经存在，否则返回`VSpaceError::MissingTable`；
+/// 目标页表项已被占用时返回`VSpaceError::SlotOccupied`；
+/// 地址没有按页面大小对齐时返回`VSpaceError::Misaligned`；
+/// 既不可读写也不可执行时返回`VSpaceError::NoRights`
+/// 
+/// Map the frame at `paddr` to `vptr` with a 4KiB, 2MiB or 1GiB page.
+pub fn map_frame(vspace_root: &mut pte_t, asid: asid_t, vptr: vptr_t, paddr: paddr_t, page_size: usize,
+                 vm_rights: usize, vm_attributes: vm_attributes_t) -> Result<(), VSpaceError> {
+    if !checkVPAlignment(page_size, vptr) || !checkVPAlignment(page_size, paddr) {
+        return Err(VSpaceError::Misaligned);
+    }
+    let pte = pte_t::make_user_pte(paddr, vm_attributes.get_execute_never() == 0, vm_rights);
+    if pte.get_vaild() == 0 {
+        return Err(VSpaceError::NoRights);
+    }
+    let ptSlot = vspace_root.lookup_slot_at_level(vptr, CONFIG_PT_LEVELS - 1 - page_size)?;
+    let slot = unsafe { &mut *ptSlot };
+    if slot.get_vaild() != 0 {
+        return Err(VSpaceError::SlotOccupied);
+    }
+    slot.update(pte, Some(vptr), Some(asid));
+    Ok(())
+}
+
+/// 选择能够同时对齐`vptr`和`paddr`、且大小不超过`len`的最大页面，返回其所在的页表级数
+#[inline]
+fn largest_page_level(vptr: vptr_t, paddr: paddr_t, len: usize) -> usize {
+    let mut level = 0;
+    while level < CONFIG_PT_LEVELS - 1 {
+        let bits = RISCV_GET_LVL_PGSIZE_BITS(level);
+        if (vptr | paddr) & MASK!(bits) == 0 && len >= BIT!(bits) {
+            break;
+        }
+        level += 1;
+    }
+    level
+}
+
+/// 选择`map_range`在`vptr`处使用的页面：从`largest_page_level`选出的页面开始，
+/// 对应的页表项已经是页目录节点时改用下一级更小的页面，返回页面所在的页表级数和页表项
+fn range_page_slot(vspace_root: &pte_t, vptr: vptr_t, paddr: paddr_t, len: usize) -> Result<(usize, *mut pte_t), VSpaceError> {
+    let mut level = largest_page_level(vptr, paddr, len);
+    loop {
+        let ptSlot = vspace_root.lookup_slot_at_level(vptr, level)?;
+        if level == CONFIG_PT_LEVELS - 1 || !unsafe { &*ptSlot }.is_pte_table() {
+            return Ok((level, ptSlot));
+        }
+        level += 1;
+    }
+}
+
+/// 将一段连续的物理地址`[pstart, pstart + len)`映射到`[vstart, vstart + len)`，
+/// 按照对齐情况贪心地选用`1GB`、`2MB`、`4KB`页面，已经存在下一级页表的位置改用更小的页面，`asid`的含义与`map_frame`相同
+/// 
+/// 如果中途失败，会撤销本次已经建立的所有映射后再返回错误；既不可读写也不可执行时返回`VSpaceError::NoRights`
+/// 
+/// Map a physically contiguous range with the largest possible pages, rolling back on failure.
+pub fn map_range(vspace_root: &mut pte_t, asid: asid_t, vstart: vptr_t, pstart: paddr_t, len: usize,
+                 vm_rights: usize, vm_attributes: vm_attributes_t) -> Result<(), VSpaceError> {
+    if (vstart | pstart | len) & MASK!(seL4_PageBits) != 0 {
+        return Err(VSpaceError::Misaligned);
+    }
+    let executable = vm_attributes.get_execute_never() == 0;
+    if pte_t::make_user_pte(pstart, executable, vm_rights).get_vaild() == 0 {
+        return Err(VSpaceError::NoRights);
+    }
+    let mut offset = 0;
+    let mut batch = FlushBatch::new();
+    while offset < len {
+        let ret = range_page_slot(vspace_root, vstart + offset, pstart + offset, len - offset).and_then(|(level, ptSlot)| {
+            let slot = unsafe { &mut *ptSlot };
+            if slot.get_vaild() != 0 {
+                return Err(VSpaceError::SlotOccupied);
+            }
+            *slot = pte_t::make_user_pte(pstart + offset, executable, vm_rights);
+            Ok(level)
+        });
+        let level = match ret {
+            Ok(level) => level,
+            Err(err) => {
+                // 已经记录的范围会在`batch`被`drop`时清除
+                unmap_mapped_prefix(vspace_root, asid, vstart, pstart, offset);
+                return Err(err);
+            }
+        };
+        add_mapping_count(asid, 1);
+        batch.add_range(Some(asid), vstart + offset, RISCV_GET_LVL_PGSIZE(level));
+        offset += RISCV_GET_LVL_PGSIZE(level);
+    }
+    batch.finish();
+    Ok(())
+}
+
+/// 撤销`map_range`已经建立的前`len`字节的映射，按与建立时相同的方式拆分页面：
+/// 本次建立的页面都是叶子节点，`range_page_slot`会选出与建立时相同的页面
+fn unmap_mapped_prefix(vspace_root: &mut pte_t, asid: asid_t, vstart: vptr_t, pstart: paddr_t, len: usize) {
+    let mut offset = 0;
+    while offset < len {
+        let Ok((level, ptSlot)) = range_page_slot(vspace_root, vstart + offset, pstart + offset, len - offset) else {
+            break;
+        };
+        unsafe {
+            *ptSlot = pte_t::pte_invalid();
+        }
+        sub_mapping_count(asid, 1);
+        offset += RISCV_GET_LVL_PGSIZE(level);
+    }
+}
+
+/// 检查`[vstart, vstart + len)`是否完全位于用户地址空间内，且两端没有落在某个大页的中间，返回范围的结束地址
+fn check_user_range(vspace_root: &pte_t, vstart: vptr_t, len: usize) -> Result<vptr_t, VSpaceError> {
+    let vend = vstart.checked_add(len).ok_or(VSpaceError::OutOfRange)?;
+    if vend > RISCV_USER_ROOT_ENTRIES() << RISCV_GET_LVL_PGSIZE_BITS(0) {
+        return Err(VSpaceError::OutOfRange);
+    }
+    if len == 0 {
+        return Ok(vend);
+    }
+    // 中间的叶子节点必然完全落在范围内，只需检查两端
+    for vptr in [vstart, vend - 1] {
+        let lu_ret = vspace_root.lookup_pt_slot(vptr);
+        let slot = unsafe { &*lu_ret.ptSlot };
+        let base = ROUND_DOWN!(vptr, lu_ret.ptBitsLeft);
+        if slot.get_vaild() != 0 && !slot.is_pte_table() && (base < vstart || base + BIT!(lu_ret.ptBitsLeft) > vend) {
+            return Err(VSpaceError::PartialPage);
+        }
+    }
+    Ok(vend)
+}
+
+/// 清除`asid`对应地址空间中`[vstart, vstart + len)`范围内的所有叶子页表项，全部清除后通过`FlushBatch`统一清除`TLB`
+/// 
+/// 范围溢出或超出用户地址空间时返回`VSpaceError::OutOfRange`；
+/// 范围的端点落在某个大页中间时返回`VSpaceError::PartialPage`，此时不会修改任何页表项
+/// 
+/// Unmap every leaf inside the range with a single TLB flush at the end.
+pub fn unmap_range(asid: asid_t, vstart: vptr_t, len: usize) -> Result<(), VSpaceError> {
+    let vspace_root = find_vspace_for_raw_asid(asid).map_err(|_| VSpaceError::InvalidRoot)?;
+    let vend = check_user_range(vspace_root, vstart, len)?;
+    let mut vptr = vstart;
+    let mut batch = FlushBatch::new();
+    while vptr < vend {
+        let lu_ret = vspace_root.lookup_pt_slot(vptr);
+        let base = ROUND_DOWN!(vptr, lu_ret.ptBitsLeft);
+        let next = base + BIT!(lu_ret.ptBitsLeft);
+        let slot = unsafe { &mut *lu_ret.ptSlot };
+        if slot.get_vaild() != 0 && !slot.is_pte_table() {
+            *slot = pte_t::pte_invalid();
+            sub_mapping_count(asid, 1);
+            batch.add_range(Some(asid), base, next - base);
+        }
+        vptr = next;
+    }
+    batch.finish();
     Ok(())
-}
\ No newline at end of file
+}
+
+/// 清除已经从页表树中摘下的页表`pt`中的所有页表项，对于页目录节点先递归清除其下一级页表，再通过`dealloc`归还该页表
+fn destroy_page_table<D: PageTableDeallocator>(pt: *mut pte_t, level: usize, dealloc: &mut D) {
+    for i in 0..BIT!(PT_INDEX_BITS) {
+        let slot = unsafe { &mut *pt.add(i) };
+        if slot.is_pte_table() && level < CONFIG_PT_LEVELS - 1 {
+            let child = slot.get_pte_from_ppn_mut() as *mut pte_t;
+            destroy_page_table(child, level + 1, dealloc);
+            dealloc.free_page_table(child as pptr_t);
+        }
+        *slot = pte_t::pte_invalid();
+    }
+}
+
+/// 销毁以`vspace_root`为根的用户地址空间：清除用户地址空间中的所有映射，并将所有中间页表交给`dealloc`回收
+/// 
+/// 根页表本身以及`copyGlobalMappings`拷贝的内核页表项保持不变；先摘下根页表中的用户页表项，
+/// 只清除一次`asid`对应的`TLB`，再回收摘下的页表，`asid`的映射页面数被清零
+/// 
+/// Tear down the user half of a vspace and hand every intermediate table back to `dealloc`.
+pub fn destroy_vspace<D: PageTableDeallocator>(vspace_root: &mut pte_t, asid: asid_t, dealloc: &mut D) {
+    let root = vspace_root as *mut pte_t;
+    // 叶子节点直接清除；页目录节点只清除`valid`位，`valid`为0时其余各位由软件使用，借此记住摘下的页表
+    for i in 0..RISCV_USER_ROOT_ENTRIES() {
+        let slot = unsafe { &mut *root.add(i) };
+        if slot.is_pte_table() {
+            slot.set_vaild(0);
+        } else {
+            *slot = pte_t::pte_invalid();
+        }
+    }
+    // 按地址清除不会清除缓存的非叶子页表项，因此清除整个`asid`，之后其他核不会再访问摘下的页表
+    sfence_vma(None, Some(asid));
+    for i in 0..RISCV_USER_ROOT_ENTRIES() {
+        let slot = unsafe { &mut *root.add(i) };
+        if slot.get_ppn() != 0 {
+            let child = slot.get_pte_from_ppn_mut() as *mut pte_t;
+            destroy_page_table(child, 1, dealloc);
+            dealloc.free_page_table(child as pptr_t);
+            *slot = pte_t::pte_invalid();
+        }
+    }
+    if let Some(meta) = get_asid_meta(asid) {
+        meta.set_mapping_count(0);
+    }
+}
+
+/// 将`src_root`用户地址空间中的页表树复制到`dst_root`中，所需的中间页表和页面均由`alloc`分配，
+/// 叶子节点按照`policy`共享、复制或标记为写时复制，`dst_asid`为`dst_root`所属的`asid`，用于记录映射的页面数
+/// 
+/// `copyGlobalMappings`会覆盖根页表中从`0x80000000`开始的页表项，因此在复制用户页表之前先为新的根页表拷贝内核页表项
+/// 
+/// 失败时`dst_root`中可能残留部分映射，调用者应使用`destroy_vspace`回收
+/// 
+/// Duplicate the user half of `src_root` into the empty vspace `dst_root`.
+pub fn clone_vspace<A: PageTableAllocator + FrameAllocator>(src_root: &mut pte_t, dst_root: &mut pte_t, dst_asid: asid_t,
+                                                            alloc: &mut A, policy: ClonePolicy) -> Result<(), VSpaceError> {
+    copyGlobalMappings(dst_root as *mut pte_t as usize);
+    let mut batch = FlushBatch::new();
+    let mut ret = Ok(());
+    let mut mapped = 0;
+    for entry in VSpaceIter::new(src_root, false, false) {
+        let dst_slot = match dst_root.lookup_or_create_slot(entry.vaddr, entry.level, alloc) {
+            Ok(ptSlot) => unsafe { &mut *ptSlot },
+            Err(err) => {
+                ret = Err(err);
+                break;
+            }
+        };
+        if dst_slot.get_vaild() != 0 {
+            ret = Err(VSpaceError::SlotOccupied);
+            break;
+        }
+        let src_slot = unsafe { &mut *entry.ptSlot };
+        let mut pte = *src_slot;
+        match policy {
+            ClonePolicy::Share => {}
+            ClonePolicy::Copy => {
+                let page_size = CONFIG_PT_LEVELS - 1 - entry.level;
+                let frame = match alloc.alloc_frame(page_size) {
+                    Some(frame) => frame,
+                    None => {
+                        ret = Err(VSpaceError::OutOfMemory);
+                        break;
+                    }
+                };
+                unsafe {
+                    core::ptr::copy_nonoverlapping(paddr_to_pptr(entry.paddr) as *const u8, frame as *mut u8,
+                                                   RISCV_GET_LVL_PGSIZE(entry.level));
+                }
+                pte.set_ppn(pptr_to_paddr(frame) >> seL4_PageBits);
+            }
+            ClonePolicy::CopyOnWrite => {
+                if pte.make_cow() {
+                    batch.add_range(None, entry.vaddr, RISCV_GET_LVL_PGSIZE(entry.level));
+                }
+                *src_slot = pte;
+            }
+        }
+        *dst_slot = pte;
+        mapped += 1;
+    }
+    // 失败时已经复制的页面仍留在`dst_root`中，同样计入
+    add_mapping_count(dst_asid, mapped);
+    batch.finish();
+    ret
+}
+
+/// 修改`asid`对应地址空间中`[vstart, vstart + len)`范围内所有叶子页表项的读写执行权限，
+/// 权限的含义与`maskVMRights`、`RISCVGetWriteFromVMRights`一致，修改完成后通过`FlushBatch`只清除被修改的页面对应的`TLB`项
+/// 
+/// 与`map_frame`一致，既不可读写也不可执行时返回`VSpaceError::NoRights`，撤销映射应使用`unmap_range`；
+/// 共享的页面不会直接获得`write`位，而是被标记为写时复制；范围的检查与`unmap_range`相同，出错时不会修改任何页表项
+/// 
+/// Change the rights of every existing leaf inside the range, mprotect-style.
+pub fn protect_range(asid: asid_t, vstart: vptr_t, len: usize, vm_rights: usize, executable: bool) -> Result<(), VSpaceError> {
+    let vspace_root = find_vspace_for_raw_asid(asid).map_err(|_| VSpaceError::InvalidRoot)?;
+    let write = RISCVGetWriteFromVMRights(vm_rights);
+    let read = RISCVGetReadFromVMRights(vm_rights);
+    if !executable && !read && !write {
+        return Err(VSpaceError::NoRights);
+    }
+    let vend = check_user_range(vspace_root, vstart, len)?;
+    let mut vptr = vstart;
+    let mut batch = FlushBatch::new();
+    while vptr < vend {
+        let lu_ret = vspace_root.lookup_pt_slot(vptr);
+        let base = ROUND_DOWN!(vptr, lu_ret.ptBitsLeft);
+        let next = base + BIT!(lu_ret.ptBitsLeft);
+        let slot = unsafe { &mut *lu_ret.ptSlot };
+        if slot.get_vaild() != 0 && !slot.is_pte_table() {
+            batch.add_range(Some(asid), base, next - base);
+            let mut pte = *slot;
+            pte.set_read(read as usize);
+            pte.set_execute(executable as usize);
+            if pte.is_shared() {
+                let sw = pte.get_sw() & !(PTE_SW_COW | PTE_SW_SHARED);
+                pte.set_sw(sw | if write { PTE_SW_COW } else { PTE_SW_SHARED });
+                pte.set_write(0);
+            } else {
+                pte.set_write(write as usize);
+            }
+            *slot = pte;
+        }
+        vptr = next;
+    }
+    batch.finish();
+    Ok(())
+}
@@ -0,0 +1,79 @@
+//! `ASIDControl`和`ASIDPool`两种`capability`的调用逻辑，出错时返回`seL4`的错误码
+use sel4_common::BIT;
+use sel4_common::sel4_config::{seL4_DeleteFirst, seL4_FailedLookup, seL4_InvalidCapability, seL4_RevokeFirst};
+use sel4_common::structures::exception_t;
+use sel4_common::utils::MAX_FREE_INDEX;
+use sel4_cspace::interface::{cap_t, cte_insert, cte_t, CapTag};
+
+use super::asid::{find_free_asid_pool_index, get_asid_pool_by_index, set_asid_pool_by_index_unchecked, Asid};
+use super::interface::copyGlobalMappings;
+
+/// `asid pool`占用的内存大小的位数，一个`asid pool`恰好占用一个`4KB`页面
+pub const seL4_ASIDPoolBits: usize = 12;
+
+/// `ASIDControl MakePool`：将`parent_slot`中的`untyped`内存清零后作为新的`asid pool`，
+/// 放入`riscvKSASIDTable`中第一个空闲的位置，并在`dest_slot`中插入对应的`asid pool cap`
+/// 
+/// 成功时返回新`asid pool`的起始`asid`
+/// 
+/// Perform ASIDControl MakePool with the untyped in `parent_slot`.
+pub fn asid_control_make_pool(parent_slot: &mut cte_t, dest_slot: &mut cte_t) -> Result<Asid, usize> {
+    let index = find_free_asid_pool_index().map_err(|_| seL4_DeleteFirst)?;
+    let untyped = parent_slot.cap;
+    if untyped.get_cap_type() != CapTag::CapUntypedCap
+        || untyped.get_untyped_block_size() != seL4_ASIDPoolBits
+        || untyped.get_untyped_is_device() != 0
+    {
+        return Err(seL4_InvalidCapability);
+    }
+    if parent_slot.ensure_no_children() != exception_t::EXCEPTION_NONE {
+        return Err(seL4_RevokeFirst);
+    }
+    if dest_slot.cap.get_cap_type() != CapTag::CapNullCap {
+        return Err(seL4_DeleteFirst);
+    }
+
+    let frame = untyped.get_untyped_ptr();
+    let asid_base = Asid::from_parts(index, 0).ok_or(seL4_DeleteFirst)?;
+    parent_slot.cap.set_untyped_free_index(MAX_FREE_INDEX(untyped.get_untyped_block_size()));
+    unsafe {
+        core::ptr::write_bytes(frame as *mut u8, 0, BIT!(seL4_ASIDPoolBits));
+    }
+    cte_insert(&cap_t::new_asid_pool_cap(asid_base.get(), frame), parent_slot, dest_slot);
+    unsafe {
+        set_asid_pool_by_index_unchecked(index, frame);
+    }
+    Ok(asid_base)
+}
+
+/// `ASIDPool Assign`：在`pool_cap`对应的`asid pool`中寻找第一个空闲的`asid`，
+/// 将`vspace_slot`中尚未映射的根页表放入该`asid`，并更新该`page table cap`的`mapped asid`
+/// 
+/// 成功时返回分配到的`asid`
+/// 
+/// Perform ASIDPool Assign with the vspace root in `vspace_slot`.
+pub fn asid_pool_assign(pool_cap: &cap_t, vspace_slot: &mut cte_t) -> Result<Asid, usize> {
+    if pool_cap.get_cap_type() != CapTag::CapASIDPoolCap {
+        return Err(seL4_InvalidCapability);
+    }
+    let mut vspace_cap = vspace_slot.cap;
+    if vspace_cap.get_cap_type() != CapTag::CapPageTableCap || vspace_cap.get_pt_is_mapped() != 0 {
+        return Err(seL4_InvalidCapability);
+    }
+    let asid_base = Asid::new(pool_cap.get_asid_base()).ok_or(seL4_InvalidCapability)?;
+    let pool = get_asid_pool_by_index(asid_base.pool_index()).ok_or(seL4_FailedLookup)?;
+    if pool.get_ptr() != pool_cap.get_asid_pool() {
+        return Err(seL4_InvalidCapability);
+    }
+    let index = pool.find_free_index(asid_base.get()).map_err(|_| seL4_DeleteFirst)?;
+    let asid = Asid::new(asid_base.get() + index).ok_or(seL4_DeleteFirst)?;
+
+    let vspace_root = vspace_cap.get_pt_base_ptr();
+    vspace_cap.set_pt_mapped_asid(asid.get());
+    vspace_cap.set_pt_mapped_address(0);
+    vspace_cap.set_pt_is_mapped(1);
+    vspace_slot.cap = vspace_cap;
+    copyGlobalMappings(vspace_root);
+    pool.set_vspace_by_asid(asid, vspace_root);
+    Ok(asid)
+}
@@ -0,0 +1,118 @@
+//! 按虚拟地址顺序遍历一个地址空间中所有有效的页表项
+use sel4_common::{BIT, MASK};
+use sel4_common::sel4_config::seL4_PageBits;
+use super::config::{CONFIG_PT_LEVELS, PT_INDEX_BITS};
+
+use super::pte::{pte_t, PteFlags};
+use super::structures::{paddr_t, vptr_t};
+use super::utils::{RISCV_GET_LVL_PGSIZE_BITS, RISCV_USER_ROOT_ENTRIES};
+
+/// `VSpaceIter`每次返回的页表项信息
+/// 
+/// `vaddr`：该页表项映射的起始虚拟地址
+/// 
+/// `paddr`：叶子节点对应页面的物理地址，或页目录节点对应下一级页表的物理地址
+/// 
+/// `level`：页表项所在的页表级数，根页表为第0级
+/// 
+/// `is_table`：是否为页目录节点
+/// 
+/// `ptSlot`：页表项的存放槽
+#[derive(Copy, Clone, Debug)]
+pub struct VSpaceEntry {
+    pub vaddr: vptr_t,
+    pub paddr: paddr_t,
+    pub level: usize,
+    pub flags: PteFlags,
+    pub is_table: bool,
+    pub ptSlot: *mut pte_t,
+}
+
+/// 从根页表开始按虚拟地址顺序遍历所有有效的叶子节点
+/// 
+/// `include_tables`为真时，同时返回途经的页目录节点（先于其下的页表项返回）；
+/// `include_kernel`为真时，同时遍历`copyGlobalMappings`拷贝的内核地址空间
+/// 
+/// Iterate over the valid mappings of a vspace in virtual address order.
+pub struct VSpaceIter {
+    tables: [*const pte_t; CONFIG_PT_LEVELS],
+    indices: [usize; CONFIG_PT_LEVELS],
+    level: usize,
+    root_end: usize,
+    include_tables: bool,
+}
+
+impl VSpaceIter {
+    pub fn new(vspace_root: &pte_t, include_tables: bool, include_kernel: bool) -> Self {
+        let mut tables = [core::ptr::null(); CONFIG_PT_LEVELS];
+        tables[0] = vspace_root as *const pte_t;
+        VSpaceIter {
+            tables,
+            indices: [0; CONFIG_PT_LEVELS],
+            level: 0,
+            root_end: if include_kernel { BIT!(PT_INDEX_BITS) } else { RISCV_USER_ROOT_ENTRIES() },
+            include_tables,
+        }
+    }
+
+    /// 由各级页表的下标拼出当前页表项对应的虚拟地址，并按最高位做符号扩展
+    fn current_vaddr(&self) -> vptr_t {
+        let mut vaddr = 0;
+        for level in 0..=self.level {
+            vaddr |= self.indices[level] << RISCV_GET_LVL_PGSIZE_BITS(level);
+        }
+        let va_bits = PT_INDEX_BITS * CONFIG_PT_LEVELS + seL4_PageBits;
+        if vaddr & BIT!(va_bits - 1) != 0 {
+            vaddr |= !MASK!(va_bits);
+        }
+        vaddr
+    }
+}
+
+impl Iterator for VSpaceIter {
+    type Item = VSpaceEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let level = self.level;
+            let end = if level == 0 { self.root_end } else { BIT!(PT_INDEX_BITS) };
+            if self.indices[level] >= end {
+                if level == 0 {
+                    return None;
+                }
+                self.level -= 1;
+                self.indices[self.level] += 1;
+                continue;
+            }
+            let ptSlot = unsafe { self.tables[level].add(self.indices[level]) as *mut pte_t };
+            let pte = unsafe { &*ptSlot };
+            if pte.get_vaild() == 0 {
+                self.indices[level] += 1;
+                continue;
+            }
+            let entry = VSpaceEntry {
+                vaddr: self.current_vaddr(),
+                paddr: pte.get_ppn() << seL4_PageBits,
+                level,
+                flags: PteFlags::from_pte(pte),
+                is_table: pte.is_pte_table(),
+                ptSlot,
+            };
+            if !entry.is_table {
+                self.indices[level] += 1;
+                return Some(entry);
+            }
+            // 最后一级页表中不应出现页目录节点，直接跳过
+            if level == CONFIG_PT_LEVELS - 1 {
+                self.indices[level] += 1;
+                continue;
+            }
+            self.tables[level + 1] = pte.get_pte_from_ppn() as *const pte_t;
+            self.indices[level + 1] = 0;
+            self.level += 1;
+            if self.include_tables {
+                return Some(entry);
+            }
+        }
+    }
+}
@@ -4,20 +4,38 @@
 #![allow(non_camel_case_types)]
 #![allow(non_upper_case_globals)]
 mod structures;
+mod config;
 mod vm_rights;
 mod satp;
 mod utils;
 mod pte;
 mod asid;
+mod iter;
+mod allocator;
+mod cow;
+mod hw_asid;
+mod flush;
+mod invocation;
 pub mod interface;
 
 pub use structures::*;
-pub use interface::{activate_kernel_vspace, rust_map_kernel_window, copyGlobalMappings, set_vm_root, unmapPage};
+pub use config::{CONFIG_PT_LEVELS, PPTR_BASE, PPTR_BASE_OFFSET, PADDR_TOP};
+pub use interface::{activate_kernel_vspace, is_kernel_vspace_active, rust_map_kernel_window, copyGlobalMappings, set_vm_root, unmapPage, translate, map_frame, map_range, unmap_range, destroy_vspace, clone_vspace, protect_range};
 pub use vm_rights::{VMReadWrite, VMReadOnly, maskVMRights};
 pub use asid::{
-    asid_t, asid_pool_t, riscvKSASIDTable, delete_asid_pool, delete_asid,
-    find_vspace_for_asid, get_asid_pool_by_index, set_asid_pool_by_index
+    asid_t, Asid, asid_pool_t, asid_meta_t, asid_meta_pool_t, riscvKSASIDTable, riscvKSASIDMeta,
+    get_asid_meta, delete_asid_pool, delete_asid,
+    find_vspace_for_asid, find_vspace_for_raw_asid, get_asid_pool_by_index, set_asid_pool_by_index,
+    set_asid_pool_by_index_unchecked, find_free_asid_pool_index
 };
-pub use utils::{pptr_to_paddr, paddr_to_pptr, kpptr_to_paddr, RISCV_GET_LVL_PGSIZE_BITS, RISCV_GET_LVL_PGSIZE, checkVPAlignment};
-pub use pte::pte_t;
-pub use satp::{sfence, setVSpaceRoot};
\ No newline at end of file
+pub use utils::{pptr_to_paddr, paddr_to_pptr, kpptr_to_paddr, RISCV_GET_LVL_PGSIZE_BITS, RISCV_USER_ROOT_ENTRIES, RISCV_GET_LVL_PGSIZE, checkVPAlignment};
+pub use pte::{pte_t, PteFlags, Translation, PTE_SW_COW, PTE_SW_SHARED};
+pub use iter::{VSpaceIter, VSpaceEntry};
+pub use allocator::{PageTableAllocator, PageTableDeallocator, FrameAllocator};
+pub use cow::{is_cow_fault, resolve_cow_fault};
+pub use satp::{satp_t, SATP_MODE, sfence, sfence_vma, sfence_range, setVSpaceRoot, current_vspace, probe_asid_bits, get_hw_asid_bits};
+#[cfg(feature = "ENABLE_SMP")]
+pub use satp::{remote_sfence_vma_range, remote_sfence_vma_asid, remote_sfence};
+pub use hw_asid::{hw_asid, assign_hw_asid, release_hw_asid, current_asid};
+pub use flush::{FlushBatch, FLUSH_BATCH_CAPACITY};
+pub use invocation::{seL4_ASIDPoolBits, asid_control_make_pool, asid_pool_assign};
\ No newline at end of file
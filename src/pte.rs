@@ -1,16 +1,22 @@
 //! 页表项的相关操作，`map``unmap`等
 use core::intrinsics::unlikely;
-use sel4_common::MASK;
-use sel4_common::sel4_config::{CONFIG_PT_LEVELS, PT_INDEX_BITS, seL4_PageBits, seL4_PageTableBits};
-use sel4_common::structures::exception_t;
+use sel4_common::{BIT, MASK};
+use sel4_common::sel4_config::{seL4_PageBits, seL4_PageTableBits};
+use super::config::{CONFIG_PT_LEVELS, PT_INDEX_BITS};
 use sel4_common::utils::{convert_to_mut_type_ref, convert_to_type_ref};
 
 
-use super::{structures::vptr_t, satp::sfence};
-use super::utils::{paddr_to_pptr, RISCV_GET_PT_INDEX};
-use super::asid::{asid_t, find_vspace_for_asid};
+use super::{structures::{vptr_t, VSpaceError}, satp::sfence_vma};
+use super::utils::{paddr_to_pptr, pptr_to_paddr, RISCV_GET_PT_INDEX};
+use super::allocator::PageTableAllocator;
+use super::asid::{asid_t, find_vspace_for_asid, Asid, add_mapping_count, sub_mapping_count};
 use super::vm_rights::{RISCVGetWriteFromVMRights, RISCVGetReadFromVMRights};
 
+/// 页表项`RSW`位中用于标记写时复制页面的位，该页面与其他地址空间共享，逻辑上可写
+pub const PTE_SW_COW: usize = 0x1;
+/// 页表项`RSW`位中用于标记共享只读页面的位，重新授予写权限时该页面需要转为写时复制
+pub const PTE_SW_SHARED: usize = 0x2;
+
 /// 页表项（`page table entry`）
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -18,6 +24,20 @@ pub struct pte_t {
     pub words: [usize; 1],
 }
 
+/// 统计`pt`开始的页表及其下各级页表中叶子节点的数量，`level`为`pt`所在的页表级数
+pub(crate) fn count_leaves(pt: *const pte_t, level: usize) -> usize {
+    (0..BIT!(PT_INDEX_BITS))
+        .map(|i| unsafe { &*pt.add(i) })
+        .map(|pte| {
+            if pte.is_pte_table() && level < CONFIG_PT_LEVELS - 1 {
+                count_leaves(pte.get_pte_from_ppn(), level + 1)
+            } else {
+                pte.is_leaf() as usize
+            }
+        })
+        .sum()
+}
+
 ///lookup_pt_slot函数的返回值，
 /// `ptSlot`：找到的虚地址对应的`pte`的存放槽
 /// `ptBitsLeft`:找到叶子节点时，虚地址剩余未被索引的位置
@@ -28,6 +48,49 @@ pub struct lookupPTSlot_ret_t {
     pub ptBitsLeft: usize,
 }
 
+/// 叶子页表项中的权限位
+/// 
+/// Permission bits of a leaf pte.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PteFlags {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    pub user: bool,
+    pub global: bool,
+}
+
+impl PteFlags {
+    #[inline]
+    pub fn from_pte(pte: &pte_t) -> Self {
+        PteFlags {
+            read: pte.get_read() != 0,
+            write: pte.get_write() != 0,
+            execute: pte.get_execute() != 0,
+            user: pte.get_user() != 0,
+            global: pte.get_global() != 0,
+        }
+    }
+}
+
+/// `translate`函数的返回值
+/// 
+/// `paddr`：虚地址对应的物理地址（包含页内偏移）
+/// 
+/// `level`：叶子节点所在的页表级数，根页表为第0级
+/// 
+/// `page_size`：叶子节点对应的页面大小（`RISCV_4K_Page`、`RISCV_Mega_Page`、`RISCV_Giga_Page`）
+/// 
+/// `ptSlot`：叶子节点的存放槽
+#[derive(Copy, Clone, Debug)]
+pub struct Translation {
+    pub paddr: usize,
+    pub level: usize,
+    pub page_size: usize,
+    pub flags: PteFlags,
+    pub ptSlot: *mut pte_t,
+}
+
 impl pte_t {
     #[inline]
     pub fn get_ptr(&self) -> usize {
@@ -75,6 +138,13 @@ impl pte_t {
         )
     }
 
+    /// 创建一个用户地址空间中指向下一级页表的页目录项（`Global=0`），
+    /// 不能使用`pte_next`，否则其下的所有映射都会被视为全局映射而不受`asid`区分
+    #[inline]
+    pub fn make_user_table_pte(paddr: usize) -> Self {
+        Self::new(paddr >> seL4_PageBits, 0, 0, 0, 0, 0, 0, 0, 0, 1)
+    }
+
     ///创建内核态页表项（`Global=1`、`User=0`）
     #[inline]
     pub fn pte_next(phys_addr: usize, is_leaf: bool) -> Self {
@@ -89,20 +159,31 @@ impl pte_t {
         )
     }
 
+    ///更新页表项，并清除`TLB`中对应的项，`vptr`和`asid`的含义与`sfence_vma`相同，
+    /// 给出`asid`时，新建或撤销叶子节点会同时更新该`asid`的映射页面数
     #[inline]
-    pub fn update(&mut self, pte: Self) {
+    pub fn update(&mut self, pte: Self, vptr: Option<vptr_t>, asid: Option<asid_t>) {
+        if let Some(asid) = asid {
+            match (self.is_leaf(), pte.is_leaf()) {
+                (false, true) => add_mapping_count(asid, 1),
+                (true, false) => sub_mapping_count(asid, 1),
+                _ => {}
+            }
+        }
         *self = pte;
-        sfence();
+        sfence_vma(vptr, asid);
     }
 
     pub fn unmap_page_table(&mut self, asid: asid_t, vptr: vptr_t) {
         let target_pt = self as *mut pte_t;
-        let find_ret = find_vspace_for_asid(asid);
-        if find_ret.status != exception_t::EXCEPTION_NONE {
+        let Some(asid) = Asid::new(asid) else {
             return;
-        }
-        assert_ne!(find_ret.vspace_root.unwrap(), target_pt);
-        let mut pt = find_ret.vspace_root.unwrap();
+        };
+        let Ok(vspace_root) = find_vspace_for_asid(asid) else {
+            return;
+        };
+        let mut pt = vspace_root as *mut pte_t;
+        assert_ne!(pt, target_pt);
         let mut ptSlot = unsafe { &mut *(pt.add(RISCV_GET_PT_INDEX(vptr, 0))) };
         let mut i = 0;
         while i < CONFIG_PT_LEVELS - 1 && pt != target_pt {
@@ -118,7 +199,37 @@ impl pte_t {
             return;
         }
         *ptSlot = pte_t::new(0, 0, 0, 0, 0, 0, 0, 0, 0, 0);
-        sfence();
+        // 该页表之下的页面随页表一起被撤销映射
+        sub_mapping_count(asid.get(), count_leaves(target_pt, i));
+        // 按地址清除不会清除缓存的非叶子页表项，因此清除整个`asid`
+        sfence_vma(None, Some(asid.get()));
+    }
+
+    ///将叶子节点标记为写时复制：可写的页面清除`write`位并置位`PTE_SW_COW`，
+    /// 只读的页面置位`PTE_SW_SHARED`，返回该页面原本是否可写
+    #[inline]
+    pub fn make_cow(&mut self) -> bool {
+        if self.get_write() == 0 {
+            if !self.is_cow() {
+                self.set_sw(self.get_sw() | PTE_SW_SHARED);
+            }
+            return false;
+        }
+        self.set_write(0);
+        self.set_sw(self.get_sw() | PTE_SW_COW);
+        true
+    }
+
+    ///判断叶子节点对应的页面是否与其他地址空间共享（写时复制或共享只读）
+    #[inline]
+    pub fn is_shared(&self) -> bool {
+        self.get_sw() & (PTE_SW_COW | PTE_SW_SHARED) != 0
+    }
+
+    ///判断是否为被标记为写时复制的叶子节点
+    #[inline]
+    pub fn is_cow(&self) -> bool {
+        self.get_vaild() != 0 && !self.is_pte_table() && self.get_sw() & PTE_SW_COW != 0
     }
 
     #[inline]
@@ -126,6 +237,12 @@ impl pte_t {
         pte_t { words: [0] }
     }
 
+    ///判断是否为有效的叶子节点
+    #[inline]
+    pub fn is_leaf(&self) -> bool {
+        self.get_vaild() != 0 && !self.is_pte_table()
+    }
+
     ///判断是页目录节点还是叶子节点，当`valid`置1，`read``write``exec`置0时，代表为叶子节点
     #[inline]
     pub fn is_pte_table(&self) -> bool {
@@ -162,16 +279,102 @@ impl pte_t {
         ret
     }
 
+    ///从当前根页表开始遍历，返回虚拟地址`vptr`在第`target_level`级页表中对应的存放槽，
+    /// 途经的每一级都必须是页目录节点
+    /// 
+    /// Find the slot of `vptr` in the `target_level` table, every level above must be a table entry.
+    pub fn lookup_slot_at_level(&self, vptr: vptr_t, target_level: usize) -> Result<*mut pte_t, VSpaceError> {
+        assert!(target_level < CONFIG_PT_LEVELS);
+        let mut pt = self as *const pte_t as *mut pte_t;
+        let mut level = 0;
+        loop {
+            let ptSlot = unsafe { pt.add(RISCV_GET_PT_INDEX(vptr, level)) };
+            if level == target_level {
+                return Ok(ptSlot);
+            }
+            let pte = unsafe { &*ptSlot };
+            if pte.get_vaild() == 0 {
+                return Err(VSpaceError::MissingTable { level });
+            }
+            if !pte.is_pte_table() {
+                return Err(VSpaceError::SlotOccupied);
+            }
+            pt = pte.get_pte_from_ppn_mut() as *mut pte_t;
+            level += 1;
+        }
+    }
+
+    ///与`lookup_slot_at_level`相同，但遇到缺失的中间页表时会通过`alloc`分配、清零并链接新的页表
+    /// 
+    /// Like `lookup_slot_at_level`, but allocate, zero and link any missing intermediate table.
+    pub fn lookup_or_create_slot<A: PageTableAllocator>(&mut self, vptr: vptr_t, target_level: usize,
+                                                        alloc: &mut A) -> Result<*mut pte_t, VSpaceError> {
+        assert!(target_level < CONFIG_PT_LEVELS);
+        let mut pt = self as *mut pte_t;
+        let mut level = 0;
+        loop {
+            let ptSlot = unsafe { pt.add(RISCV_GET_PT_INDEX(vptr, level)) };
+            if level == target_level {
+                return Ok(ptSlot);
+            }
+            let slot = unsafe { &mut *ptSlot };
+            if slot.get_vaild() == 0 {
+                let table = alloc.alloc_page_table().ok_or(VSpaceError::OutOfMemory)?;
+                unsafe {
+                    core::ptr::write_bytes(table as *mut u8, 0, BIT!(seL4_PageTableBits));
+                }
+                *slot = pte_t::make_user_table_pte(pptr_to_paddr(table));
+            } else if !slot.is_pte_table() {
+                return Err(VSpaceError::SlotOccupied);
+            }
+            pt = slot.get_pte_from_ppn_mut() as *mut pte_t;
+            level += 1;
+        }
+    }
+
     #[inline]
     pub fn get_vaild(&self) -> usize {
         (self.words[0] & 0x1) >> 0
     }
 
+    #[inline]
+    pub fn set_vaild(&mut self, valid: usize) {
+        self.words[0] &= !0x1usize;
+        self.words[0] |= valid & 0x1usize;
+    }
+
     #[inline]
     pub fn get_ppn(&self) -> usize {
         (self.words[0] & 0x3f_ffff_ffff_fc00usize) >> 10
     }
 
+    #[inline]
+    pub fn set_ppn(&mut self, ppn: usize) {
+        self.words[0] &= !0x3f_ffff_ffff_fc00usize;
+        self.words[0] |= (ppn << 10) & 0x3f_ffff_ffff_fc00usize;
+    }
+
+    #[inline]
+    pub fn get_sw(&self) -> usize {
+        (self.words[0] & 0x300usize) >> 8
+    }
+
+    #[inline]
+    pub fn set_sw(&mut self, sw: usize) {
+        self.words[0] &= !0x300usize;
+        self.words[0] |= (sw << 8) & 0x300usize;
+    }
+
+    #[inline]
+    pub fn get_global(&self) -> usize {
+        (self.words[0] & 0x20usize) >> 5
+    }
+
+    #[inline]
+    pub fn get_user(&self) -> usize {
+        (self.words[0] & 0x10usize) >> 4
+    }
+
     #[inline]
     pub fn get_execute(&self) -> usize {
         (self.words[0] & 0x8usize) >> 3
@@ -182,6 +385,24 @@ impl pte_t {
         (self.words[0] & 0x4usize) >> 2
     }
 
+    #[inline]
+    pub fn set_write(&mut self, write: usize) {
+        self.words[0] &= !0x4usize;
+        self.words[0] |= (write << 2) & 0x4usize;
+    }
+
+    #[inline]
+    pub fn set_execute(&mut self, execute: usize) {
+        self.words[0] &= !0x8usize;
+        self.words[0] |= (execute << 3) & 0x8usize;
+    }
+
+    #[inline]
+    pub fn set_read(&mut self, read: usize) {
+        self.words[0] &= !0x2usize;
+        self.words[0] |= (read << 1) & 0x2usize;
+    }
+
     #[inline]
     pub fn get_read(&self) -> usize {
         (self.words[0] & 0x2usize) >> 1
@@ -1,6 +1,32 @@
 use riscv::register::satp;
-use sel4_common::sbi::remote_sfence_vma;
-use super::structures::paddr_t;
+use sel4_common::sel4_config::seL4_PageBits;
+use super::config::CONFIG_PT_LEVELS;
+use super::structures::{paddr_t, pptr_t, vptr_t};
+use super::utils::paddr_to_pptr;
+use super::asid::asid_t;
+use super::hw_asid::{assign_hw_asid, enough_hw_asids, hw_asid};
+
+/// `SBI v0.1`中`remote sfence.vma`的调用号
+#[cfg(feature = "ENABLE_SMP")]
+const SBI_REMOTE_SFENCE_VMA: usize = 6;
+
+/// `SBI v0.1`中带`asid`的`remote sfence.vma`调用号
+#[cfg(feature = "ENABLE_SMP")]
+const SBI_REMOTE_SFENCE_VMA_ASID: usize = 7;
+
+/// 需要清除的页面数量超过该值时，`sfence_range`改为清除整个`asid`对应的`TLB`项
+pub(crate) const SFENCE_RANGE_MAX_PAGES: usize = 64;
+
+/// `satp`中的分页模式，由页表级数决定：`Sv39`为8，`Sv48`为9，`Sv57`为10
+pub const SATP_MODE: usize = match CONFIG_PT_LEVELS {
+    3 => 8,
+    4 => 9,
+    5 => 10,
+    _ => panic!("unsupported CONFIG_PT_LEVELS"),
+};
+
+/// 硬件实际支持的`asid`位数，由`probe_asid_bits`在启动时探测，探测之前视为不支持`asid`
+static mut hwASIDBits: usize = 0;
 
 ///`satp`寄存器对应的内存备份
 #[repr(C)]
@@ -9,28 +35,58 @@ pub struct satp_t {
     pub words: usize,
 }
 
+/// `satp`中`asid`字段的位置和掩码
+const SATP_ASID_SHIFT: usize = 44;
+const SATP_ASID_MASK: usize = 0xffff;
+
 impl satp_t {
     pub fn new(mode: usize, asid: usize, ppn: usize) -> Self {
         satp_t {
             words: 0
                 | (mode & 0xfusize) << 60
-                | (asid & 0xffffusize) << 44
+                | (asid & SATP_ASID_MASK) << SATP_ASID_SHIFT
                 | (ppn & 0xfffffffffffusize) << 0,
         }
     }
+
+    #[inline]
+    pub fn get_mode(&self) -> usize {
+        (self.words >> 60) & 0xfusize
+    }
+
+    /// `satp`中的硬件`asid`标签，不是`seL4 asid`，两者的对应关系见`hw_asid`，
+    /// 本核当前的`seL4 asid`可以通过`current_asid`获得
+    #[inline]
+    pub fn get_asid(&self) -> usize {
+        (self.words >> SATP_ASID_SHIFT) & SATP_ASID_MASK
+    }
+
+    #[inline]
+    pub fn get_ppn(&self) -> usize {
+        self.words & 0xfffffffffffusize
+    }
+
+    /// 根页表的物理地址
+    #[inline]
+    pub fn get_root_paddr(&self) -> paddr_t {
+        self.get_ppn() << seL4_PageBits
+    }
+
+    /// 根页表在`PSpace`中的虚拟地址
+    #[inline]
+    pub fn get_root_pptr(&self) -> pptr_t {
+        paddr_to_pptr(self.get_root_paddr())
+    }
 }
 
 #[cfg(feature = "ENABLE_SMP")]
 #[inline]
 pub fn sfence() {
-    use sel4_common::smp::get_sbi_mask_for_all_remote_harts;
-
     unsafe {
         core::arch::asm!("fence w, rw");
     }
     sfence_local();
-    let mask = get_sbi_mask_for_all_remote_harts();
-    remote_sfence_vma(mask, 0, 0);
+    remote_sfence(None, 0, 0, None);
 }
 
 #[cfg(feature = "ENABLE_SMP")]
@@ -41,6 +97,75 @@ pub fn sfence_local() {
     }
 }
 
+/// 请求`hart_mask`中的核清除`asid`地址空间内`[start, start + size)`范围对应的`TLB`项，
+/// 这里的`asid`为硬件`asid`，`start`和`size`都为0时清除该`asid`的全部项
+/// 
+/// `sel4_common::sbi::sbi_call`只能传递三个参数，因此这里单独发起`ecall`
+/// 
+/// SBI remote sfence.vma scoped to an asid.
+#[cfg(feature = "ENABLE_SMP")]
+pub fn remote_sfence_vma_asid(hart_mask: usize, start: usize, size: usize, asid: usize) {
+    let virt_addr_hart_mask = (&hart_mask) as *const usize as usize;
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("x10") virt_addr_hart_mask => _,
+            in("x11") start,
+            in("x12") size,
+            in("x13") asid,
+            in("x17") SBI_REMOTE_SFENCE_VMA_ASID,
+        );
+    }
+}
+
+/// 请求`hart_mask`中的核清除`[start, start + size)`范围对应的所有`asid`的`TLB`项，
+/// `start`和`size`都为0时清除全部项
+/// 
+/// `sel4_common::sbi::remote_sfence_vma`会忽略`start`和`size`，因此这里直接发起调用
+/// 
+/// SBI remote sfence.vma that keeps the range.
+#[cfg(feature = "ENABLE_SMP")]
+pub fn remote_sfence_vma_range(hart_mask: usize, start: usize, size: usize) {
+    let virt_addr_hart_mask = (&hart_mask) as *const usize as usize;
+    sel4_common::sbi::sbi_call(SBI_REMOTE_SFENCE_VMA, virt_addr_hart_mask, start, size);
+}
+
+/// 请求其他核清除`[start, start + size)`范围内`asid`对应的`TLB`项，`asid`的含义与`sfence_vma`相同
+/// 
+/// `hart_mask`为`SBI`使用的按`hart id`表示的掩码；为`None`时：给出`asid`则只发送给`asid_meta_t`中记录过激活该`asid`的核，
+/// 否则发送给所有其他核
+/// 
+/// Remote TLB shootdown with address and asid granularity.
+#[cfg(feature = "ENABLE_SMP")]
+pub fn remote_sfence(hart_mask: Option<usize>, start: usize, size: usize, asid: Option<asid_t>) {
+    use sel4_common::smp::{cpuIndexToID, get_sbi_mask_for_all_remote_harts};
+    use sel4_common::sel4_config::CONFIG_MAX_NUM_NODES;
+    use sel4_common::BIT;
+    use super::asid::get_asid_meta;
+
+    let Ok(hw_asid) = sfence_hw_asid(asid) else {
+        return;
+    };
+    let hart_mask = match (hart_mask, asid) {
+        (Some(hart_mask), _) => hart_mask,
+        (None, Some(asid)) => {
+            // `asid_meta_t`中的核掩码按核的下标记录，需要转换为`hart id`
+            let cpu_mask = get_asid_meta(asid).map_or(0, |meta| meta.get_hart_mask());
+            (0..CONFIG_MAX_NUM_NODES)
+                .filter(|&cpu| cpu_mask & BIT!(cpu) != 0)
+                .fold(0, |mask, cpu| mask | BIT!(cpuIndexToID(cpu)))
+        }
+        (None, None) => !0,
+    } & get_sbi_mask_for_all_remote_harts();
+    if hart_mask == 0 {
+        return;
+    }
+    match hw_asid {
+        Some(hw_asid) => remote_sfence_vma_asid(hart_mask, start, size, hw_asid),
+        None => remote_sfence_vma_range(hart_mask, start, size),
+    }
+}
+
 ///对汇编指令`sfence.vma`的简单封装，清空`cache`、`tlb`
 /// 
 /// Risc-v's sfence.vma 
@@ -52,16 +177,136 @@ pub fn sfence() {
     }
 }
 
-///设置页表，创建一个新的satp的值，然后将其写入satp寄存器
+/// 将`seL4 asid`转换为`sfence.vma`使用的硬件`asid`，`asid`为`None`时表示所有`asid`
+/// 
+/// 返回`Err`表示该`asid`在当前代中没有硬件`asid`，`TLB`中不会有它的项，无需清除；
+/// 硬件不支持`asid`时退化为所有`asid`
+#[inline]
+fn sfence_hw_asid(asid: Option<asid_t>) -> Result<Option<usize>, ()> {
+    match asid.map(hw_asid) {
+        None => Ok(None),
+        Some(Some(hw_asid)) => Ok(Some(hw_asid)),
+        Some(None) if get_hw_asid_bits() == 0 => Ok(None),
+        Some(None) => Err(()),
+    }
+}
+
+/// 在本核上执行`sfence.vma`，`vaddr`和`hw_asid`为`None`时对应`x0`
+#[inline]
+fn sfence_vma_local(vaddr: Option<usize>, hw_asid: Option<usize>) {
+    unsafe {
+        match (vaddr, hw_asid) {
+            (None, None) => core::arch::asm!("sfence.vma"),
+            (Some(vaddr), None) => core::arch::asm!("sfence.vma {0}, x0", in(reg) vaddr),
+            (None, Some(hw_asid)) => core::arch::asm!("sfence.vma x0, {0}", in(reg) hw_asid),
+            (Some(vaddr), Some(hw_asid)) => core::arch::asm!("sfence.vma {0}, {1}", in(reg) vaddr, in(reg) hw_asid),
+        }
+    }
+}
+
+///清除`TLB`中的项：`vaddr`为`Some`时只清除映射该地址的叶子项，`asid`为`Some`时只清除该`asid`的项，
+/// 两者都为`None`时等同于`sfence`
+/// 
+/// 注意按地址清除不会清除缓存的非叶子页表项，删除页表时应只按`asid`清除；
+/// 多核下先执行`fence w, rw`，保证其他核收到清除请求时能看到之前对页表的修改，单核下`sfence.vma`本身已保证顺序
+/// 
+/// sfence.vma scoped to an optional address and an optional asid.
+pub fn sfence_vma(vaddr: Option<usize>, asid: Option<asid_t>) {
+    let Ok(hw_asid) = sfence_hw_asid(asid) else {
+        return;
+    };
+    #[cfg(feature = "ENABLE_SMP")]
+    unsafe {
+        core::arch::asm!("fence w, rw");
+    }
+    sfence_vma_local(vaddr, hw_asid);
+    #[cfg(feature = "ENABLE_SMP")]
+    remote_sfence(None, vaddr.unwrap_or(0), vaddr.map_or(0, |_| 1 << seL4_PageBits), asid);
+}
+
+///清除`TLB`中`[vstart, vstart + len)`范围对应的项，`asid`的含义与`sfence_vma`相同，
+/// 范围较大时改为清除整个`asid`对应的项，`fence`的规则与`sfence_vma`相同
+/// 
+/// Flush the TLB entries of the range, optionally in the given asid.
+pub fn sfence_range(vstart: vptr_t, len: usize, asid: Option<asid_t>) {
+    let Ok(hw_asid) = sfence_hw_asid(asid) else {
+        return;
+    };
+    #[cfg(feature = "ENABLE_SMP")]
+    unsafe {
+        core::arch::asm!("fence w, rw");
+    }
+    if len >> seL4_PageBits > SFENCE_RANGE_MAX_PAGES {
+        sfence_vma_local(None, hw_asid);
+    } else {
+        let mut vptr = vstart;
+        while vptr < vstart + len {
+            sfence_vma_local(Some(vptr), hw_asid);
+            vptr += 1 << seL4_PageBits;
+        }
+    }
+    #[cfg(feature = "ENABLE_SMP")]
+    remote_sfence(None, vstart, len, asid);
+}
+
+///设置页表，创建一个新的satp的值，然后将其写入satp寄存器，
+/// 其中`asid`会先通过`assign_hw_asid`映射为硬件`asid`，如果硬件不支持`asid`，则清空整个`TLB`
 /// 
 /// Assign addr to satp.
 #[inline]
 #[no_mangle]
 pub fn setVSpaceRoot(addr: paddr_t, asid: usize) {
-    let satp = satp_t::new(8usize, asid, addr >> 12);
-    satp::write(satp.words);
-    #[cfg(not(feature = "ENABLE_SMP"))]
-    sfence();
-    #[cfg(feature = "ENABLE_SMP")]
-    sfence_local();
+    match assign_hw_asid(asid) {
+        Some(hw_asid) => {
+            let satp = satp_t::new(SATP_MODE, hw_asid, addr >> 12);
+            satp::write(satp.words);
+        }
+        None => {
+            let satp = satp_t::new(SATP_MODE, 0, addr >> 12);
+            satp::write(satp.words);
+            #[cfg(not(feature = "ENABLE_SMP"))]
+            sfence();
+            #[cfg(feature = "ENABLE_SMP")]
+            sfence_local();
+        }
+    }
+}
+
+/// 读取`satp`寄存器，获得本核当前使用的地址空间，其中的`asid`为硬件`asid`
+/// 
+/// Read satp and decode the vspace the hart is running in.
+#[inline]
+pub fn current_vspace() -> satp_t {
+    satp_t { words: satp::read().bits() }
+}
+
+/// 在启动时探测硬件实际支持的`asid`位数：向`satp`的`asid`字段写入全1后读回，
+/// 探测结果会被保存，供`setVSpaceRoot`、`hwASIDFlush`等使用；硬件`asid`不足以按代分配时视为不支持`asid`，记为0
+/// 
+/// `Bare`模式下`asid`字段的读回值没有定义，此时不探测，直接返回已有的结果，因此需要在开启分页之后调用，见`activate_kernel_vspace`
+/// 
+/// Probe the number of asid bits implemented by the hart.
+pub fn probe_asid_bits() -> usize {
+    let old = satp::read().bits();
+    if (satp_t { words: old }).get_mode() == 0 {
+        return get_hw_asid_bits();
+    }
+    satp::write(old | (0xffffusize << 44));
+    let bits = ((satp::read().bits() >> 44) & 0xffffusize).count_ones() as usize;
+    satp::write(old);
+    // 探测期间使用全1的`asid`，清除可能以该`asid`缓存的项
+    unsafe {
+        core::arch::asm!("sfence.vma");
+    }
+    let bits = if enough_hw_asids(bits) { bits } else { 0 };
+    unsafe {
+        hwASIDBits = bits;
+    }
+    bits
+}
+
+/// 获得`probe_asid_bits`探测到的可用的硬件`asid`位数
+#[inline]
+pub fn get_hw_asid_bits() -> usize {
+    unsafe { hwASIDBits }
 }
\ No newline at end of file
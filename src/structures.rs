@@ -42,4 +42,91 @@ impl vm_attributes_t {
         self.words[0] &= !0x1usize;
         self.words[0] |= (v64 << 0) & 0x1usize;
     }
-}
\ No newline at end of file
+}
+
+/// 页表遍历、映射等操作返回的错误类型，`level`与`RISCV_GET_PT_INDEX`一致，根页表为第0级
+/// 
+/// Errors of the page table walking and mapping operations. Level 0 is the root table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VSpaceError {
+    /// 页表遍历在第`level`级遇到了无效的页表项
+    /// 
+    /// The walk reached an invalid entry at `level`.
+    NotMapped { level: usize },
+    /// 第`level`级的页表项无效，缺少下一级页表
+    /// 
+    /// The entry at `level` is invalid, the next level table is missing.
+    MissingTable { level: usize },
+    /// 目标页表项已被占用
+    /// 
+    /// The target slot already holds a valid entry.
+    SlotOccupied,
+    /// 虚拟地址或物理地址没有按页面大小对齐
+    /// 
+    /// The virtual or physical address is not aligned to the page size.
+    Misaligned,
+    /// 分配页表或页面失败
+    /// 
+    /// The allocator ran out of memory.
+    OutOfMemory,
+    /// 该页面没有被标记为写时复制
+    /// 
+    /// The page is not marked copy-on-write.
+    NotCopyOnWrite,
+    /// 映射请求既不可读写也不可执行，无法生成有效的页表项
+    /// 
+    /// The requested mapping is neither readable, writable nor executable.
+    NoRights,
+    /// `asid`没有对应的地址空间
+    /// 
+    /// No vspace is assigned to the asid.
+    InvalidRoot,
+    /// 地址范围溢出或超出了用户地址空间
+    /// 
+    /// The range overflows or reaches beyond the user half of the vspace.
+    OutOfRange,
+    /// 范围的端点落在一个大页的中间，该大页只有一部分在范围内
+    /// 
+    /// An end of the range falls inside a superpage that is only partially covered.
+    PartialPage,
+}
+
+
+/// `clone_vspace`复制叶子节点时采用的策略
+/// 
+/// How `clone_vspace` duplicates the frames of the source vspace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClonePolicy {
+    /// 新地址空间与原地址空间共享相同的页面
+    /// 
+    /// Map the same frames into the new vspace.
+    Share,
+    /// 通过`PSpace`窗口将页面内容复制到新分配的页面中
+    /// 
+    /// Copy the frame contents into newly allocated frames.
+    Copy,
+    /// 共享相同的页面，并将两边可写的页面都标记为写时复制
+    /// 
+    /// Share the frames and mark the writable ones copy-on-write on both sides.
+    CopyOnWrite,
+}
+
+
+/// `asid`分配相关操作返回的错误类型
+/// 
+/// Errors of the asid allocator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AsidError {
+    /// `riscvKSASIDTable`中已没有空闲的`asid pool`下标
+    /// 
+    /// Every entry of `riscvKSASIDTable` is in use.
+    NoFreePool,
+    /// `asid pool`中已没有空闲的`asid`
+    /// 
+    /// Every asid of the pool is in use.
+    PoolFull,
+    /// 下标超出了`riscvKSASIDTable`或`asid pool`的范围
+    /// 
+    /// The index is out of the bounds of `riscvKSASIDTable` or the pool.
+    OutOfRange,
+}
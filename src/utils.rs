@@ -1,12 +1,11 @@
 use super::structures::paddr_t;
-use sel4_common::sel4_config::{
-    seL4_PageBits, CONFIG_PT_LEVELS, KERNEL_ELF_BASE_OFFSET, PPTR_BASE_OFFSET, PT_INDEX_BITS,
-};
+use sel4_common::sel4_config::{seL4_PageBits, KERNEL_ELF_BASE_OFFSET};
+use super::config::{CONFIG_PT_LEVELS, PPTR_BASE, PPTR_BASE_OFFSET, PT_INDEX_BITS};
 use sel4_common::utils::pageBitsForSize;
 use sel4_common::{BIT, MASK};
 
-///获得虚拟地址`addr`对应的`n`级VPN，
-/// 具体对应关系为:
+///获得虚拟地址`addr`对应的`n`级VPN，`n`为0时对应根页表，
+/// 即`VPN[CONFIG_PT_LEVELS - 1 - n]`，`Sv39`中具体对应关系为:
 /// ```
 /// VPN[2] <=> n = 0
 /// VPN[1] <=> n = 1
@@ -18,7 +17,7 @@ pub fn RISCV_GET_PT_INDEX(addr: usize, n: usize) -> usize {
         & MASK!(PT_INDEX_BITS)
 }
 
-/// 获得第n级页表对应的虚拟地址空间的大小位数
+/// 获得第n级页表对应的虚拟地址空间的大小位数，以`Sv39`为例：
 /// 根页表对应2^30=1GB,30位
 /// 一级页表对应2^21=2MB，21位
 /// 二级页表对应2^12=4KB，12位
@@ -29,7 +28,7 @@ pub fn RISCV_GET_LVL_PGSIZE_BITS(n: usize) -> usize {
     ((PT_INDEX_BITS) * (((CONFIG_PT_LEVELS) - 1) - (n))) + seL4_PageBits
 }
 
-/// 获得第n级页表对应的虚拟地址空间的大小
+/// 获得第n级页表对应的虚拟地址空间的大小，以`Sv39`为例：
 /// 根页表对应2^30=1GB,30位
 /// 一级页表对应2^21=2MB，21位
 /// 二级页表对应2^12=4KB，12位
@@ -40,6 +39,15 @@ pub fn RISCV_GET_LVL_PGSIZE(n: usize) -> usize {
     BIT!(RISCV_GET_LVL_PGSIZE_BITS(n))
 }
 
+/// 根页表中用户地址空间所占的页表项数量，
+/// 下标不小于该值的页表项属于内核地址空间，由`copyGlobalMappings`从内核页表拷贝而来
+/// 
+/// Number of root entries that belong to the user half of a vspace.
+#[inline]
+pub fn RISCV_USER_ROOT_ENTRIES() -> usize {
+    RISCV_GET_PT_INDEX(PPTR_BASE, 0)
+}
+
 ///在`reL4`内核页表中，内核代码，在内核地址空间中被映射了两次，
 /// 一次映射到`KERNEL_ELF_BASE`开始的虚拟地址上，
 /// 由于整个物理地址空间会在内核虚拟地址空间中做一次完整的映射，映射到`PPTR_BASE`开始的虚拟地址上，